@@ -11,7 +11,7 @@ use lazy_static::lazy_static;
 use mozjs_sys::jsapi::JSObject;
 use runtime::globals::fetch::RequestInfo;
 
-use crate::{ion_err, ion_mk_err};
+use crate::{ion_err, ion_mk_err, metrics::METRICS};
 
 use super::{Cache, CacheQueryOptions};
 
@@ -83,10 +83,12 @@ impl CacheStorage {
             };
 
             if !responses.is_empty() {
+                METRICS.cache_lookup(true);
                 return Promise::resolved(cx, responses[0]);
             }
         }
 
+        METRICS.cache_lookup(false);
         Promise::resolved(cx, Value::undefined(cx))
     }
 
@@ -20,7 +20,7 @@ use runtime::{
     promise::future_to_promise,
 };
 
-use crate::ion_err;
+use crate::{ion_err, metrics::METRICS};
 
 use self::cache_storage::CacheEntryList;
 
@@ -301,6 +301,7 @@ impl Cache {
                 &options.unwrap_or_default(),
             )
             .map(|r| {
+                METRICS.cache_lookup(!r.is_empty());
                 if !r.is_empty() {
                     ObjectValue(r[0])
                 } else {
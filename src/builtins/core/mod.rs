@@ -58,6 +58,16 @@ fn set_promise_hooks(
     RESOLVE.set(Some(PermanentHeap::from_local(&resolve)));
 }
 
+/// Drops the promise hooks, which belong to the runtime on this thread. Has
+/// to be called before that runtime goes away.
+pub fn release_promise_hooks() {
+    CALLBACKS_REGISTERED.set(false);
+    INIT.set(None);
+    BEFORE.set(None);
+    AFTER.set(None);
+    RESOLVE.set(None);
+}
+
 fn call_handler(
     handler: &'static std::thread::LocalKey<RefCell<Option<PermanentHeap<*mut JSFunction>>>>,
     cx: *mut JSContext,
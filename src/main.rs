@@ -6,19 +6,21 @@ use std::{
     net::{IpAddr, SocketAddr},
     path::PathBuf,
     pin::Pin,
+    process::ExitCode,
+    time::Duration,
 };
 
-#[cfg(not(target_os = "wasi"))]
-use std::time::Duration;
-
 use anyhow::Context as _;
 use clap::{Parser, ValueEnum};
 use request_handlers::{
-    cloudflare::CloudflareRequestHandler, wintercg::WinterCGRequestHandler, Either, UserCode,
+    cloudflare::CloudflareRequestHandler, wintercg::WinterCGRequestHandler, Either, UserCodeSource,
 };
 
-use server::BoxedDynRunner;
-use tokio::{join, task::LocalSet};
+use server::{
+    AccessLogFormat, BoxedDynRunner, IpCidr, ListenAddr, ProxyProtocolMode, ShutdownOutcome,
+    TrustedProxies,
+};
+use tokio::{task::LocalSet, try_join};
 
 #[macro_use]
 extern crate ion_proc;
@@ -46,18 +48,27 @@ macro_rules! js_try {
 }
 
 mod builtins;
+mod metrics;
 mod request_handlers;
 mod runners;
 mod server;
 mod sm_utils;
 
-fn main() {
-    if let Err(e) = run() {
-        println!("{e:?}");
+/// Exit code used when the shutdown timeout was reached and requests were
+/// still being handled.
+const EXIT_SHUTDOWN_TIMED_OUT: u8 = 2;
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(code) => code,
+        Err(e) => {
+            println!("{e:?}");
+            ExitCode::FAILURE
+        }
     }
 }
 
-fn run() -> Result<(), anyhow::Error> {
+fn run() -> Result<ExitCode, anyhow::Error> {
     // Initialize logging.
     if std::env::var("RUST_LOG").is_err() {
         // Set default log level.
@@ -65,6 +76,11 @@ fn run() -> Result<(), anyhow::Error> {
     }
     tracing_subscriber::fmt::init();
 
+    // This has to happen before any threads are started, since it modifies
+    // the environment.
+    #[cfg(unix)]
+    let systemd_listen_fds = crate::server::SystemdListenFds::take_from_env()?;
+
     let args = match Args::try_parse() {
         Ok(a) => a,
         Err(err1) => {
@@ -87,7 +103,32 @@ fn run() -> Result<(), anyhow::Error> {
                 .set(runtime::config::Config::default().log_level(runtime::config::LogLevel::Error))
                 .unwrap();
 
-            runners::exec::exec_script(cmd.js_path, cmd.script)
+            runners::exec::exec_script(cmd.js_path, cmd.script)?;
+            Ok(ExitCode::SUCCESS)
+        }
+
+        Cmd::Check(cmd) => {
+            runtime::config::CONFIG
+                .set(runtime::config::Config::default().log_level(runtime::config::LogLevel::Error))
+                .unwrap();
+
+            let user_code = UserCodeSource {
+                path: cmd.js_path.clone(),
+                script_mode: cmd.script,
+            }
+            .load()?;
+
+            match cmd.mode {
+                Some(HandlerName::Cloudflare) => {
+                    runners::check::check_code(CloudflareRequestHandler, user_code)?
+                }
+                Some(HandlerName::WinterCG) | None => {
+                    runners::check::check_code(WinterCGRequestHandler, user_code)?
+                }
+            }
+
+            println!("No errors found in {}", cmd.js_path.display());
+            Ok(ExitCode::SUCCESS)
         }
 
         Cmd::Serve(cmd) => {
@@ -111,19 +152,123 @@ fn run() -> Result<(), anyhow::Error> {
                 8080
             };
 
-            let addr: SocketAddr = (interface, port).into();
-            let config = crate::server::ServerConfig { addr };
+            let listen = if !cmd.listen.is_empty() {
+                cmd.listen
+            } else {
+                let addr: SocketAddr = (interface, port).into();
+                match (cmd.tls_cert.is_some(), cmd.tls_port) {
+                    (true, Some(tls_port)) => vec![
+                        ListenAddr::Tcp(addr),
+                        ListenAddr::Tls((interface, tls_port).into()),
+                    ],
+                    (true, None) => vec![ListenAddr::Tls(addr)],
+                    (false, _) => vec![ListenAddr::Tcp(addr)],
+                }
+            };
+
+            let tls = match (cmd.tls_cert, cmd.tls_key) {
+                (Some(cert_path), Some(key_path)) => Some(crate::server::TlsConfig {
+                    cert_path,
+                    key_path,
+                }),
+                _ => None,
+            };
+
+            let http2 = crate::server::Http2Config {
+                enabled: !cmd.http1_only,
+                max_concurrent_streams: cmd.http2_max_concurrent_streams,
+                initial_stream_window_size: cmd.http2_stream_window_size,
+                initial_connection_window_size: cmd.http2_connection_window_size,
+            };
+
+            let limits = crate::server::LimitsConfig {
+                max_body_size: cmd.max_body_size,
+                max_header_size: cmd.max_header_size,
+                header_read_timeout: Some(Duration::from_secs(cmd.header_read_timeout))
+                    .filter(|t| !t.is_zero()),
+                keep_alive_timeout: Some(Duration::from_secs(cmd.keep_alive_timeout))
+                    .filter(|t| !t.is_zero()),
+                max_connections: cmd.max_connections,
+            };
+
+            if cmd.proxy_protocol == Some(ProxyProtocolMode::Optional)
+                && cmd.trusted_proxies.is_empty()
+            {
+                anyhow::bail!("--proxy-protocol=optional requires --trusted-proxies");
+            }
+
+            let config = crate::server::ServerConfig {
+                listen,
+                tls,
+                unix_socket_mode: cmd.unix_socket_mode,
+                http2,
+                limits,
+                proxy_protocol: cmd.proxy_protocol,
+                trusted_proxies: TrustedProxies::new(cmd.trusted_proxies),
+                default_host: cmd.default_host,
+                access_log: cmd.access_log.map(|format| crate::server::AccessLogConfig {
+                    format,
+                    path: cmd.access_log_file,
+                }),
+                metrics_listen: cmd.metrics_listen,
+                admin_listen: cmd.admin_listen,
+                health_path: cmd.health_path,
+                ready_path: cmd.ready_path,
+                drain_grace_period: Duration::from_secs(cmd.drain_grace_period),
+                #[cfg(unix)]
+                systemd_listen_fds,
+            };
 
             runtime::config::CONFIG
                 .set(runtime::config::Config::default().log_level(runtime::config::LogLevel::Error))
                 .unwrap();
 
-            let user_code = UserCode::from_path(&cmd.js_path, cmd.script)?;
+            if cmd.min_js_threads == 0 || cmd.min_js_threads > cmd.max_js_threads {
+                anyhow::bail!(
+                    "--min-js-threads must be between 1 and --max-js-threads ({})",
+                    cmd.max_js_threads
+                );
+            }
+            let request_limits = runners::limits::RequestLimits {
+                timeout: Some(Duration::from_secs(cmd.request_timeout)).filter(|t| !t.is_zero()),
+                cpu_budget: Some(Duration::from_millis(cmd.request_cpu_budget_ms))
+                    .filter(|t| !t.is_zero()),
+            };
+            let heap = sm_utils::HeapConfig {
+                max_bytes: cmd.max_heap_mb.map(|mb| mb * 1024 * 1024),
+                max_nursery_bytes: cmd.gc_nursery_mb.map(|mb| mb * 1024 * 1024),
+                slice_budget: cmd.gc_slice_budget_ms.map(Duration::from_millis),
+            };
+            let runtime = runners::RuntimeConfig {
+                heap,
+                isolate_per_request: cmd.isolate_per_request,
+            };
+            let pool = runners::single::WorkerPoolConfig {
+                min_threads: cmd.min_js_threads,
+                max_threads: cmd.max_js_threads,
+                idle_timeout: Some(Duration::from_secs(cmd.js_thread_idle_timeout))
+                    .filter(|t| !t.is_zero()),
+                policy: cmd.scheduling_policy,
+                affinity: cmd.affinity,
+                max_in_flight_per_thread: cmd.max_inflight_per_thread,
+                max_queued_requests: cmd.max_queued_requests,
+                request_limits,
+                runtime,
+                max_requests_per_thread: cmd.max_requests_per_worker,
+                max_thread_age: cmd.max_worker_age.map(Duration::from_secs),
+            };
+
+            let user_code_source = UserCodeSource {
+                path: cmd.js_path,
+                script_mode: cmd.script,
+            };
+            let user_code = user_code_source.load()?;
+            let watch_source = cmd.watch.then(|| user_code_source.clone());
 
             let runner: Either<
                 BoxedDynRunner,
                 (
-                    BoxedDynRunner,
+                    runners::inline::InlineRunner,
                     Pin<Box<dyn runners::inline::InlineRunnerRequestHandlerFuture>>,
                 ),
             > = match (cmd.mode, cmd.single_threaded) {
@@ -132,9 +277,10 @@ fn run() -> Result<(), anyhow::Error> {
                     Either::Left(Box::new(
                         runners::single::SingleRunner::new_request_handler(
                             CloudflareRequestHandler,
-                            cmd.max_js_threads,
+                            pool,
                             user_code,
-                        ),
+                            user_code_source,
+                        )?,
                     ))
                 }
                 (Some(HandlerName::Cloudflare), true) => {
@@ -142,17 +288,20 @@ fn run() -> Result<(), anyhow::Error> {
                     let (runner, future) = runners::inline::InlineRunner::new_request_handler(
                         CloudflareRequestHandler,
                         user_code,
+                        request_limits,
+                        runtime,
                     );
-                    Either::Right((Box::new(runner), Box::pin(future)))
+                    Either::Right((runner, Box::pin(future)))
                 }
                 (Some(HandlerName::WinterCG) | None, false) => {
                     tracing::info!("Starting in WinterCG mode");
                     Either::Left(Box::new(
                         runners::single::SingleRunner::new_request_handler(
                             WinterCGRequestHandler,
-                            cmd.max_js_threads,
+                            pool,
                             user_code,
-                        ),
+                            user_code_source,
+                        )?,
                     ))
                 }
                 (Some(HandlerName::WinterCG) | None, true) => {
@@ -160,17 +309,18 @@ fn run() -> Result<(), anyhow::Error> {
                     let (runner, future) = runners::inline::InlineRunner::new_request_handler(
                         WinterCGRequestHandler,
                         user_code,
+                        request_limits,
+                        runtime,
                     );
-                    Either::Right((Box::new(runner), Box::pin(future)))
+                    Either::Right((runner, Box::pin(future)))
                 }
             };
 
-            #[cfg_attr(target_os = "wasi", allow(unused))]
             let (tx, rx) = tokio::sync::oneshot::channel();
 
             // There are two main points to consider here:
-            // * ctrlc is not available on WASIX and signal handling in
-            //   general is not stable and fully wired up yet.
+            // * Signal handling under WASIX is not stable and fully wired
+            //   up yet.
             // * When running under WASIX, we expect 99% of usage to be
             //   either local development or running on Wasmer Edge.
             //   Wasmer Edge already keeps instances alive while they're
@@ -179,7 +329,7 @@ fn run() -> Result<(), anyhow::Error> {
             // Given the two points above, clean shutdown is implemented
             // for native builds only.
             #[cfg(not(target_os = "wasi"))]
-            {
+            let shutdown = {
                 let timeout = cmd
                     .shutdown_timeout
                     .map(Duration::from_secs)
@@ -190,35 +340,43 @@ fn run() -> Result<(), anyhow::Error> {
                     Some(timeout)
                 };
 
-                let runner_clone = match runner {
-                    Either::Left(ref r) => Either::Left(r.clone()),
-                    Either::Right((ref r, _)) => Either::Right(r.clone()),
+                let drain_grace_period = Duration::from_secs(cmd.drain_grace_period);
+
+                let runner_clone: BoxedDynRunner = match runner {
+                    Either::Left(ref r) => r.clone(),
+                    Either::Right((ref r, _)) => Box::new(r.clone()),
                 };
-                let mut shutdown_future = Some(async move {
-                    match runner_clone {
-                        Either::Left(r) => r.shutdown(timeout).await,
-                        Either::Right(r) => r.shutdown(timeout).await,
-                    }
+                async move {
+                    wait_for_shutdown_signal().await?;
+                    // The server keeps accepting connections for the grace
+                    // period, then stops and drains the existing ones while
+                    // the workers finish their requests.
                     _ = tx.send(());
-                });
-                ctrlc::set_handler(move || {
-                    if let Some(f) = shutdown_future.take() {
-                        tokio::runtime::Builder::new_current_thread()
-                            .enable_all()
-                            .build()
-                            .unwrap()
-                            .block_on(f);
-                    }
-                })
-                .expect("Failed to set Ctrl-C handler");
-            }
+                    tokio::time::sleep(drain_grace_period).await;
+                    anyhow::Ok(runner_clone.shutdown(timeout).await)
+                }
+            };
+            #[cfg(target_os = "wasi")]
+            let shutdown = async move {
+                // The server shuts down once the sender is dropped, so keep
+                // it around for good.
+                let _tx = tx;
+                std::future::pending::<anyhow::Result<ShutdownOutcome>>().await
+            };
 
-            match runner {
+            let outcome = match runner {
                 Either::Left(runner) => tokio::runtime::Builder::new_multi_thread()
                     .enable_all()
                     .build()
                     .expect("Failed building the Runtime")
-                    .block_on(crate::server::run_server(config, runner, rx)),
+                    .block_on(async move {
+                        if let Some(source) = watch_source {
+                            tokio::spawn(runners::watch::reload_on_changes(source, runner.clone()));
+                        }
+                        let server_future = crate::server::run_server(config, runner, rx);
+                        let ((), outcome) = try_join!(server_future, shutdown)?;
+                        anyhow::Ok(outcome)
+                    })?,
                 Either::Right((runner, runner_future)) => {
                     tokio::runtime::Builder::new_current_thread()
                         .enable_all()
@@ -228,19 +386,65 @@ fn run() -> Result<(), anyhow::Error> {
                             let local_set = LocalSet::new();
                             local_set
                                 .run_until(async move {
+                                    // The code is evaluated by the runner future, so it
+                                    // needs to be running before we can find out if
+                                    // that worked.
+                                    let runner_task = tokio::task::spawn_local(runner_future);
+                                    runner.wait_until_started().await?;
+
                                     let server_future =
-                                        crate::server::run_server(config, runner, rx);
-                                    let (result, ()) = join!(server_future, runner_future);
-                                    result
+                                        crate::server::run_server(config, Box::new(runner), rx);
+                                    let runner_future = async move {
+                                        runner_task.await?;
+                                        anyhow::Ok(())
+                                    };
+                                    let ((), outcome, ()) =
+                                        try_join!(server_future, shutdown, runner_future)?;
+                                    anyhow::Ok(outcome)
                                 })
                                 .await
-                        })
+                        })?
                 }
+            };
+
+            match outcome {
+                ShutdownOutcome::Completed => Ok(ExitCode::SUCCESS),
+                ShutdownOutcome::TimedOut => Ok(ExitCode::from(EXIT_SHUTDOWN_TIMED_OUT)),
             }
         }
     }
 }
 
+/// Resolves once the process is asked to terminate, with Ctrl+C or with
+/// SIGTERM or SIGQUIT as sent by container runtimes and process supervisors.
+#[cfg(not(target_os = "wasi"))]
+async fn wait_for_shutdown_signal() -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut interrupt =
+            signal(SignalKind::interrupt()).context("Failed to listen for SIGINT")?;
+        let mut terminate =
+            signal(SignalKind::terminate()).context("Failed to listen for SIGTERM")?;
+        let mut quit = signal(SignalKind::quit()).context("Failed to listen for SIGQUIT")?;
+
+        let name = tokio::select! {
+            _ = interrupt.recv() => "SIGINT",
+            _ = terminate.recv() => "SIGTERM",
+            _ = quit.recv() => "SIGQUIT",
+        };
+        tracing::info!("Received {name}");
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c()
+        .await
+        .context("Failed to listen for Ctrl+C")?;
+
+    Ok(())
+}
+
 /// winterjs CLI
 #[derive(clap::Parser, Debug)]
 #[clap(version)]
@@ -254,6 +458,7 @@ struct Args {
 enum Cmd {
     Serve(CmdServe),
     Exec(CmdExec),
+    Check(CmdCheck),
 }
 
 /// Start a WinterJS webserver serving the given JS app.
@@ -268,14 +473,306 @@ struct CmdServe {
     #[clap(long, default_value = "127.0.0.1", env = "WINTERJS_IP")]
     ip: Option<IpAddr>,
 
+    /// Addresses to listen on, in the form of tcp://<ip>:<port>,
+    /// tls://<ip>:<port> or unix:<path>. May be specified multiple times.
+    /// Overrides --port, --ip and --tls-port. When started through systemd
+    /// socket activation, the inherited sockets are used instead.
+    #[clap(
+        long,
+        env = "WINTERJS_LISTEN",
+        value_delimiter = ',',
+        conflicts_with_all = ["port", "ip", "tls_port"]
+    )]
+    listen: Vec<ListenAddr>,
+
+    /// File mode for Unix domain sockets created by --listen, in octal
+    /// notation (e.g. 660).
+    #[clap(long, env = "WINTERJS_UNIX_SOCKET_MODE", value_parser = server::parse_unix_socket_mode)]
+    unix_socket_mode: Option<u32>,
+
+    /// Expect a PROXY protocol (v1 or v2) header at the start of every
+    /// connection, and use the client address it carries. In required mode,
+    /// which is the default when no value is given, connections without a
+    /// valid header are rejected. In optional mode, only connections from
+    /// --trusted-proxies must have one, and connections from anyone else are
+    /// served without looking for one.
+    #[clap(
+        long,
+        env = "WINTERJS_PROXY_PROTOCOL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "required"
+    )]
+    proxy_protocol: Option<ProxyProtocolMode>,
+
+    /// Comma-separated list of proxy addresses or CIDR ranges, such as
+    /// 10.0.0.0/8. When a request comes from one of these, the client
+    /// address is taken from the Forwarded or X-Forwarded-For header instead,
+    /// and the request URL from the Forwarded or X-Forwarded-Proto and
+    /// X-Forwarded-Host headers.
+    #[clap(long, env = "WINTERJS_TRUSTED_PROXIES", value_delimiter = ',')]
+    trusted_proxies: Vec<IpCidr>,
+
+    /// The host to use in request URLs when the client doesn't send a Host
+    /// header.
+    #[clap(
+        long,
+        env = "WINTERJS_DEFAULT_HOST",
+        default_value = "app.wasmer.internal"
+    )]
+    default_host: http::uri::Authority,
+
+    /// Path to a PEM-encoded TLS certificate chain. If specified along with
+    /// --tls-key, the server will terminate TLS itself. The certificate is
+    /// reloaded automatically when the files change on disk.
+    #[clap(long, env = "WINTERJS_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// Path to the PEM-encoded private key for --tls-cert.
+    #[clap(long, env = "WINTERJS_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// The port to serve HTTPS on. If specified, plain HTTP will still be
+    /// served on --port. Otherwise, --port will serve HTTPS only.
+    #[clap(long, env = "WINTERJS_TLS_PORT", requires = "tls_cert")]
+    tls_port: Option<u16>,
+
+    /// Only accept HTTP/1.x connections. By default, HTTP/2 is negotiated
+    /// through ALPN when serving HTTPS, and cleartext HTTP/2 (h2c) is
+    /// accepted from clients that use prior knowledge.
+    #[clap(long, env = "WINTERJS_HTTP1_ONLY")]
+    http1_only: bool,
+
+    /// Maximum number of concurrent streams per HTTP/2 connection.
+    #[clap(long, env = "WINTERJS_HTTP2_MAX_CONCURRENT_STREAMS")]
+    http2_max_concurrent_streams: Option<u32>,
+
+    /// Initial HTTP/2 flow control window size for each stream, in bytes.
+    #[clap(long, env = "WINTERJS_HTTP2_STREAM_WINDOW_SIZE")]
+    http2_stream_window_size: Option<u32>,
+
+    /// Initial HTTP/2 flow control window size for each connection, in bytes.
+    #[clap(long, env = "WINTERJS_HTTP2_CONNECTION_WINDOW_SIZE")]
+    http2_connection_window_size: Option<u32>,
+
+    /// Maximum size of a request body, in bytes. Requests with a larger
+    /// Content-Length are rejected with 413 Payload Too Large up front,
+    /// while bodies of unknown length are streamed, and cut off with a 413
+    /// once they exceed it. Unlimited by default.
+    #[clap(long, env = "WINTERJS_MAX_BODY_SIZE")]
+    max_body_size: Option<u64>,
+
+    /// Maximum size of the request line and headers, in bytes. Larger
+    /// requests are rejected with 431 Request Header Fields Too Large.
+    #[clap(
+        long,
+        env = "WINTERJS_MAX_HEADER_SIZE",
+        default_value = "65536",
+        value_parser = clap::value_parser!(u32).range(8192..)
+    )]
+    max_header_size: u32,
+
+    /// How long clients get to send the request headers, in seconds,
+    /// counted from when the connection is accepted. Slower clients get a
+    /// 408 Request Timeout. Pass in zero to disable the
+    /// timeout.
+    #[clap(long, env = "WINTERJS_HEADER_READ_TIMEOUT", default_value = "30")]
+    header_read_timeout: u64,
+
+    /// How long to keep idle connections open, in seconds. Pass in zero to
+    /// disable the timeout.
+    #[clap(long, env = "WINTERJS_KEEP_ALIVE_TIMEOUT", default_value = "75")]
+    keep_alive_timeout: u64,
+
+    /// Maximum number of connections to serve at the same time. Further
+    /// connections wait until a slot frees up. Unlimited by default.
+    #[clap(
+        long,
+        env = "WINTERJS_MAX_CONNECTIONS",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    max_connections: Option<usize>,
+
+    /// Write an access log line for every request, in the given format.
+    #[clap(long, env = "WINTERJS_ACCESS_LOG")]
+    access_log: Option<AccessLogFormat>,
+
+    /// File to write the access log to, instead of stdout. The file is
+    /// reopened on SIGHUP, for use with logrotate; SIGHUP does nothing else,
+    /// the code is reloaded on SIGUSR2 instead.
+    #[clap(long, env = "WINTERJS_ACCESS_LOG_FILE", requires = "access_log")]
+    access_log_file: Option<PathBuf>,
+
+    /// Address to serve Prometheus metrics on, at /metrics. Metrics are not
+    /// exposed unless this is set.
+    #[clap(long, env = "WINTERJS_METRICS_LISTEN")]
+    metrics_listen: Option<SocketAddr>,
+
+    /// Address to accept administrative calls on. `POST /reload` loads the
+    /// JavaScript code again, like SIGUSR2 does. Don't expose this publicly.
+    #[clap(long, env = "WINTERJS_ADMIN_LISTEN")]
+    admin_listen: Option<SocketAddr>,
+
+    /// Path of the liveness endpoint, which fails once all JavaScript worker
+    /// threads have died. It's answered without running any JavaScript code.
+    #[clap(
+        long,
+        env = "WINTERJS_HEALTH_PATH",
+        default_value = "/__winterjs/health"
+    )]
+    health_path: String,
+
+    /// Path of the readiness endpoint, which fails until the JavaScript code
+    /// was evaluated successfully, and again once shutdown has begun.
+    #[clap(long, env = "WINTERJS_READY_PATH", default_value = "/__winterjs/ready")]
+    ready_path: String,
+
     /// Maximum amount of Javascript worker threads to spawn.
     #[clap(long, default_value = "16", env = "WINTERJS_MAX_JS_THREADS")]
     max_js_threads: usize,
 
-    // /// Watch the Javascript file for changes and automatically reload.
-    // #[clap(short, long, env = "WINTERJS_WATCH")]
-    // watch: bool,
-    /// Path to a Javascript file to serve.
+    /// Amount of Javascript worker threads to start and initialize before
+    /// accepting requests. These are kept around even when idle.
+    #[clap(long, default_value = "1", env = "WINTERJS_MIN_JS_THREADS")]
+    min_js_threads: usize,
+
+    /// How long a Javascript worker thread beyond --min-js-threads may go
+    /// without handling requests before it's stopped, in seconds. Pass in
+    /// zero to keep them running forever.
+    #[clap(long, default_value = "60", env = "WINTERJS_JS_THREAD_IDLE_TIMEOUT")]
+    js_thread_idle_timeout: u64,
+
+    /// How requests are spread over the Javascript worker threads:
+    /// least-in-flight uses the first idle thread, spawning a new one when
+    /// all are busy; round-robin takes turns between the threads;
+    /// power-of-two-choices picks the less busy of two random threads; and
+    /// spawn-above:<N> works like least-in-flight, but only spawns a new
+    /// thread once every thread has more than N requests in flight.
+    #[clap(
+        long,
+        env = "WINTERJS_SCHEDULING_POLICY",
+        default_value = "least-in-flight"
+    )]
+    scheduling_policy: runners::dispatcher::SchedulingPolicy,
+
+    /// Send all requests with the same key to the same Javascript worker
+    /// thread, so they share its in-memory state. The key can be a request
+    /// header (header:<name>), a cookie (cookie:<name>) or the client's IP
+    /// address (client-ip). Requests without the key are scheduled as usual.
+    /// Keys only stay on the same thread while no threads are started or
+    /// stopped, so set --min-js-threads to --max-js-threads to keep them
+    /// fixed.
+    #[clap(long, env = "WINTERJS_AFFINITY")]
+    affinity: Option<runners::dispatcher::AffinityKey>,
+
+    /// Maximum amount of requests a single Javascript worker thread handles
+    /// at once. When all threads are at the limit, requests wait in a queue
+    /// until one of them has room.
+    #[clap(
+        long,
+        env = "WINTERJS_MAX_INFLIGHT_PER_THREAD",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    max_inflight_per_thread: Option<u32>,
+
+    /// Maximum amount of requests waiting for a Javascript worker thread
+    /// when all of them are at --max-inflight-per-thread. Requests beyond
+    /// that are answered with 503 Service Unavailable and a Retry-After
+    /// header. Unlimited if not specified.
+    #[clap(
+        long,
+        env = "WINTERJS_MAX_QUEUED_REQUESTS",
+        requires = "max_inflight_per_thread"
+    )]
+    max_queued_requests: Option<usize>,
+
+    /// How long the Javascript code may take to respond to a request, in
+    /// seconds, before it's interrupted and the request is answered with
+    /// 504 Gateway Timeout. A worker thread that can't be interrupted is
+    /// replaced. Pass in zero to disable the timeout.
+    #[clap(long, default_value = "0", env = "WINTERJS_REQUEST_TIMEOUT")]
+    request_timeout: u64,
+
+    /// How much CPU time the Javascript code may use to respond to a
+    /// request, in milliseconds, before it's interrupted and the request is
+    /// answered with 500 Internal Server Error. Time spent on promise
+    /// reactions is split between all requests in flight on the thread.
+    /// Pass in zero to disable the budget.
+    #[clap(long, default_value = "0", env = "WINTERJS_REQUEST_CPU_BUDGET_MS")]
+    request_cpu_budget_ms: u64,
+
+    /// Maximum size of the Javascript heap of each worker thread, in
+    /// megabytes. A thread that runs out of memory fails the requests it's
+    /// handling and restarts with a fresh heap. Unlimited if not specified.
+    #[clap(
+        long,
+        env = "WINTERJS_MAX_HEAP_MB",
+        value_parser = clap::value_parser!(u32).range(1..4096)
+    )]
+    max_heap_mb: Option<u32>,
+
+    /// Maximum size of the nursery, where new Javascript objects are
+    /// allocated before they're collected or moved to the main heap, in
+    /// megabytes. A bigger nursery means fewer, but longer, minor GCs.
+    #[clap(
+        long,
+        env = "WINTERJS_GC_NURSERY_MB",
+        value_parser = clap::value_parser!(u32).range(1..4096)
+    )]
+    gc_nursery_mb: Option<u32>,
+
+    /// How long each slice of an incremental garbage collection may take,
+    /// in milliseconds. Shorter slices mean shorter pauses in request
+    /// handling, but more of them.
+    #[clap(long, env = "WINTERJS_GC_SLICE_BUDGET_MS")]
+    gc_slice_budget_ms: Option<u64>,
+
+    /// Evaluate the Javascript code again in a new global object, in a
+    /// realm of its own, for every request, so no global state is shared
+    /// between requests. Each worker thread handles one request at a time in
+    /// this mode, which costs a lot of throughput.
+    #[clap(long, env = "WINTERJS_ISOLATE_PER_REQUEST")]
+    isolate_per_request: bool,
+
+    /// Replace a Javascript worker thread with a fresh one after it handled
+    /// this many requests, to get rid of anything the code leaked. The new
+    /// thread evaluates the code before taking over, while the old one
+    /// finishes the requests it already has. Not supported in
+    /// single-threaded mode.
+    #[clap(
+        long,
+        env = "WINTERJS_MAX_REQUESTS_PER_WORKER",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with = "single_threaded"
+    )]
+    max_requests_per_worker: Option<u64>,
+
+    /// Replace a Javascript worker thread with a fresh one after it ran for
+    /// this long, in seconds. Threads started together are replaced at
+    /// slightly different times. Not supported in single-threaded mode.
+    #[clap(
+        long,
+        env = "WINTERJS_MAX_WORKER_AGE",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with = "single_threaded"
+    )]
+    max_worker_age: Option<u64>,
+
+    /// Watch the Javascript code for changes and automatically reload. In
+    /// module mode, every module imported from the entry point is watched.
+    /// Not supported in single-threaded mode.
+    #[clap(
+        short,
+        long,
+        env = "WINTERJS_WATCH",
+        conflicts_with = "single_threaded"
+    )]
+    watch: bool,
+
+    /// Path to a Javascript file to serve. On SIGUSR2, the code is loaded
+    /// again and new requests are handled by the new version once it has
+    /// been initialized. SIGHUP does not reload the code, it only reopens
+    /// the access log file. Not supported in single-threaded mode.
     #[clap(env = "WINTERJS_PATH")]
     js_path: PathBuf,
 
@@ -285,7 +782,10 @@ struct CmdServe {
     script: bool,
 
     /// The operating mode of the server. Defaults to WinterCG mode if left
-    /// out.
+    /// out. Fetch event listeners get the client address as
+    /// `FetchEvent.clientAddress` in both modes. Cloudflare mode also sets
+    /// the CF-Connecting-IP request header, which WinterCG mode leaves as
+    /// the client sent it.
     #[clap(short = 'H', long, env = "WINTERJS_MODE")]
     mode: Option<HandlerName>,
 
@@ -296,11 +796,20 @@ struct CmdServe {
 
     #[cfg(not(target_os = "wasi"))]
     /// Clean shutdown timeout, i.e. how long to wait before forcefully
-    /// terminating request handler threads after Ctrl+C is pressed, in
-    /// seconds. Pass in zero to disable the timeout. Defaults to 60
-    /// seconds.
+    /// terminating request handler threads after Ctrl+C is pressed or
+    /// SIGTERM or SIGQUIT is received, in seconds. Pass in zero to disable
+    /// the timeout. Defaults to 60 seconds. WinterJS exits with code 2 if
+    /// the timeout is reached.
     #[clap(short = 't', long, env = "WINTERJS_SHUTDOWN_TIMEOUT")]
     shutdown_timeout: Option<u64>,
+
+    /// How long to keep accepting connections after shutdown starts, in
+    /// seconds. The readiness endpoint fails right away, so load balancers
+    /// have this long to stop sending requests before the listeners close
+    /// and new connections are refused. The shutdown timeout starts after
+    /// it.
+    #[clap(long, env = "WINTERJS_DRAIN_GRACE_PERIOD", default_value = "0")]
+    drain_grace_period: u64,
 }
 
 /// Execute a JS file directly and exit. This is useful for cron jobs, etc.
@@ -316,6 +825,25 @@ struct CmdExec {
     script: bool,
 }
 
+/// Evaluate a JS app the way the server would, without serving it, and
+/// report any errors. Exits with a non-zero code if evaluation fails.
+#[derive(clap::Parser, Debug)]
+struct CmdCheck {
+    /// Path to the Javascript code to check.
+    #[clap(env = "WINTERJS_PATH")]
+    js_path: PathBuf,
+
+    /// Run in script mode. If this flag is not specified, the JS file will
+    /// be loaded in module mode instead.
+    #[clap(short, long, env = "WINTERJS_SCRIPT")]
+    script: bool,
+
+    /// The operating mode of the server. Defaults to WinterCG mode if left
+    /// out.
+    #[clap(short = 'H', long, env = "WINTERJS_MODE")]
+    mode: Option<HandlerName>,
+}
+
 #[derive(Debug, Clone, ValueEnum)]
 pub enum HandlerName {
     WinterCG,
@@ -0,0 +1,270 @@
+//! Process-wide metrics, rendered in the Prometheus text exposition format.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI32, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+/// Upper bounds of the request duration histogram buckets, in seconds.
+const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<u16, Histogram>>,
+    workers: Mutex<Vec<Arc<WorkerCounters>>>,
+    script_errors: AtomicU64,
+    shed_requests: AtomicU64,
+    out_of_memory_restarts: AtomicU64,
+    cancelled_requests: Mutex<BTreeMap<&'static str, u64>>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+}
+
+/// Counters kept by the runner for each worker thread.
+#[derive(Default)]
+pub struct WorkerCounters {
+    /// Requests sent to the thread that haven't been answered yet, which
+    /// includes the ones still queued up for it.
+    pub in_flight_requests: AtomicI32,
+    pub dispatched_requests: AtomicU64,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; DURATION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Metrics {
+    pub fn record_request(&self, status: http::StatusCode, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let mut requests = self.requests.lock();
+        let histogram = requests.entry(status.as_u16()).or_default();
+        for (bucket, le) in histogram.buckets.iter_mut().zip(DURATION_BUCKETS) {
+            if seconds <= le {
+                *bucket += 1;
+            }
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    /// Registers the counters of the worker thread with the given index,
+    /// replacing the ones of any previous thread with that index.
+    pub fn set_worker(&self, index: usize, counters: Arc<WorkerCounters>) {
+        let mut workers = self.workers.lock();
+        if index >= workers.len() {
+            workers.resize_with(index + 1, Default::default);
+        }
+        workers[index] = counters;
+    }
+
+    /// Forgets the worker threads from index `len` on, when they're stopped
+    /// or replaced by a smaller set.
+    pub fn truncate_workers(&self, len: usize) {
+        self.workers.lock().truncate(len);
+    }
+
+    pub fn script_error(&self) {
+        self.script_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn request_shed(&self) {
+        self.shed_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn shed_requests(&self) -> u64 {
+        self.shed_requests.load(Ordering::Relaxed)
+    }
+
+    pub fn worker_out_of_memory(&self) {
+        self.out_of_memory_restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn request_cancelled(&self, reason: &'static str) {
+        *self.cancelled_requests.lock().entry(reason).or_default() += 1;
+    }
+
+    pub fn cache_lookup(&self, hit: bool) {
+        let counter = if hit {
+            &self.cache_hits
+        } else {
+            &self.cache_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "winterjs_requests_total",
+            "counter",
+            "Requests handled, by response status.",
+        );
+        let requests = self.requests.lock();
+        for (status, histogram) in requests.iter() {
+            _ = writeln!(
+                out,
+                "winterjs_requests_total{{status=\"{status}\"}} {}",
+                histogram.count
+            );
+        }
+
+        header(
+            &mut out,
+            "winterjs_request_duration_seconds",
+            "histogram",
+            "Time until the response headers were ready, by response status.",
+        );
+        for (status, histogram) in requests.iter() {
+            for (count, le) in histogram.buckets.iter().zip(DURATION_BUCKETS) {
+                _ = writeln!(
+                    out,
+                    "winterjs_request_duration_seconds_bucket{{status=\"{status}\",le=\"{le}\"}} {count}"
+                );
+            }
+            _ = writeln!(
+                out,
+                "winterjs_request_duration_seconds_bucket{{status=\"{status}\",le=\"+Inf\"}} {}",
+                histogram.count
+            );
+            _ = writeln!(
+                out,
+                "winterjs_request_duration_seconds_sum{{status=\"{status}\"}} {}",
+                histogram.sum
+            );
+            _ = writeln!(
+                out,
+                "winterjs_request_duration_seconds_count{{status=\"{status}\"}} {}",
+                histogram.count
+            );
+        }
+        drop(requests);
+
+        let workers = self.workers.lock();
+        header(
+            &mut out,
+            "winterjs_worker_threads",
+            "gauge",
+            "Number of JavaScript worker threads spawned.",
+        );
+        _ = writeln!(out, "winterjs_worker_threads {}", workers.len());
+
+        header(
+            &mut out,
+            "winterjs_worker_in_flight_requests",
+            "gauge",
+            "Requests queued up or being handled, by worker thread.",
+        );
+        for (index, counters) in workers.iter().enumerate() {
+            _ = writeln!(
+                out,
+                "winterjs_worker_in_flight_requests{{worker=\"{index}\"}} {}",
+                counters.in_flight_requests.load(Ordering::Relaxed)
+            );
+        }
+
+        header(
+            &mut out,
+            "winterjs_worker_requests_total",
+            "counter",
+            "Requests dispatched, by worker thread.",
+        );
+        for (index, counters) in workers.iter().enumerate() {
+            _ = writeln!(
+                out,
+                "winterjs_worker_requests_total{{worker=\"{index}\"}} {}",
+                counters.dispatched_requests.load(Ordering::Relaxed)
+            );
+        }
+        drop(workers);
+
+        header(
+            &mut out,
+            "winterjs_script_errors_total",
+            "counter",
+            "Requests that failed because the script could not be evaluated.",
+        );
+        _ = writeln!(
+            out,
+            "winterjs_script_errors_total {}",
+            self.script_errors.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "winterjs_shed_requests_total",
+            "counter",
+            "Requests turned away because all worker threads and the queue were full.",
+        );
+        _ = writeln!(
+            out,
+            "winterjs_shed_requests_total {}",
+            self.shed_requests.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "winterjs_out_of_memory_restarts_total",
+            "counter",
+            "Times a worker thread ran out of memory and restarted its runtime.",
+        );
+        _ = writeln!(
+            out,
+            "winterjs_out_of_memory_restarts_total {}",
+            self.out_of_memory_restarts.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "winterjs_cancelled_requests_total",
+            "counter",
+            "Requests that were cancelled before the script responded, by reason.",
+        );
+        for (reason, count) in self.cancelled_requests.lock().iter() {
+            _ = writeln!(
+                out,
+                "winterjs_cancelled_requests_total{{reason=\"{reason}\"}} {count}"
+            );
+        }
+
+        header(
+            &mut out,
+            "winterjs_cache_lookups_total",
+            "counter",
+            "Cache API lookups, by result.",
+        );
+        _ = writeln!(
+            out,
+            "winterjs_cache_lookups_total{{result=\"hit\"}} {}",
+            self.cache_hits.load(Ordering::Relaxed)
+        );
+        _ = writeln!(
+            out,
+            "winterjs_cache_lookups_total{{result=\"miss\"}} {}",
+            self.cache_misses.load(Ordering::Relaxed)
+        );
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    _ = writeln!(out, "# HELP {name} {help}");
+    _ = writeln!(out, "# TYPE {name} {kind}");
+}
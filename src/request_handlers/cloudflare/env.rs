@@ -127,7 +127,13 @@ impl EnvAssets {
                 };
 
                 let (parts, body) = http_req.into_parts();
-                let request = super::super::Request { parts, body };
+                // This request never left the worker, so there is no
+                // real client address to speak of
+                let request = super::super::Request {
+                    parts,
+                    body,
+                    remote_addr: (std::net::Ipv4Addr::LOCALHOST, 0).into(),
+                };
 
                 let url = url::Url::parse(request.parts.uri.to_string().as_str())?;
                 let (cx, response) = cx
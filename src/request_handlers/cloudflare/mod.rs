@@ -258,7 +258,8 @@ impl ByRefStandardModules for CloudflareStandardModules {
     }
 }
 
-const WORKER_JS_SEARCH_PATHS: &[&str] = &["_worker.js", "_worker/index.js", "_worker.js/index.js"];
+pub(crate) const WORKER_JS_SEARCH_PATHS: &[&str] =
+    &["_worker.js", "_worker/index.js", "_worker.js/index.js"];
 
 fn discover_worker_js(root: impl AsRef<Path>) -> Result<Option<PathBuf>> {
     for path in WORKER_JS_SEARCH_PATHS {
@@ -314,15 +315,22 @@ fn eval_module(cx: &Context, path: impl AsRef<Path>) -> anyhow::Result<Cloudflar
 
 fn start_request(
     cx: &Context,
-    request: Request,
+    mut request: Request,
     module: Option<&CloudflareCodeModule>,
 ) -> Result<Either<PendingResponse, ReadyResponse>> {
+    // Like Cloudflare, we always overwrite the header, so clients can't
+    // spoof it.
+    let client_address = request.remote_addr.ip();
+    request.parts.headers.insert(
+        "cf-connecting-ip",
+        http::HeaderValue::from_str(&client_address.to_string())?,
+    );
+
+    let request = super::build_fetch_request(cx, request)?;
+
     match module.and_then(|m| m.fetch_function.as_ref()) {
         Some(func) => {
-            let request = Value::object(
-                cx,
-                &cx.root(super::build_fetch_request(cx, request)?).into(),
-            );
+            let request = Value::object(cx, &cx.root(request).into());
             let env = Value::object(cx, &cx.root(env::Env::new_obj(cx)).into());
             let ctx = Value::object(cx, &cx.root(context::Context::new_obj(cx)).into());
             let result = Function::from(func.root(cx))
@@ -341,7 +349,7 @@ fn start_request(
             }
         }
 
-        None => super::service_workers::start_request(cx, request),
+        None => super::service_workers::dispatch_fetch_event(cx, request, client_address),
     }
 }
 
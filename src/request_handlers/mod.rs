@@ -1,4 +1,4 @@
-use std::{ffi::OsString, path::PathBuf, pin::Pin};
+use std::{ffi::OsString, net::SocketAddr, path::PathBuf, pin::Pin};
 
 use anyhow::{anyhow, bail, Context as _, Result};
 use futures::Future;
@@ -55,9 +55,25 @@ impl UserCode {
     }
 }
 
+/// Where the user code was loaded from, so it can be loaded again when
+/// reloading.
+#[derive(Clone, Debug)]
+pub struct UserCodeSource {
+    pub path: PathBuf,
+    pub script_mode: bool,
+}
+
+impl UserCodeSource {
+    pub fn load(&self) -> anyhow::Result<UserCode> {
+        UserCode::from_path(&self.path, self.script_mode)
+    }
+}
+
 pub struct Request {
     pub parts: http::request::Parts,
     pub body: hyper::Body,
+    /// The address of the client, after taking trusted proxies into account.
+    pub remote_addr: SocketAddr,
 }
 
 pub enum Either<A, B> {
@@ -168,7 +184,13 @@ impl StandardModules for Box<dyn ByRefStandardModules> {
     }
 }
 
+/// The server reconstructs the full URI of incoming requests, so this only
+/// needs to fill in the blanks for requests created some other way.
 fn build_request_uri(request: &Request) -> Result<Uri> {
+    if request.parts.uri.scheme().is_some() && request.parts.uri.authority().is_some() {
+        return Ok(request.parts.uri.clone());
+    }
+
     let host = get_host(&request.parts.uri, &request.parts.headers)?;
     Uri::builder()
         .scheme("http")
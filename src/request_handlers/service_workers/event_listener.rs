@@ -30,6 +30,12 @@ fn add_event_listener<'cx: 'f, 'f>(event: String, callback: Function<'f>) -> ion
     })
 }
 
+/// Drops the registered listener, which belongs to the runtime on this
+/// thread. Has to be called before that runtime goes away.
+pub fn release_event_callback() {
+    EVENT_CALLBACK.set(None);
+}
+
 pub fn invoke_fetch_event_callback<'cx>(
     cx: &'cx Context,
     args: &[Value],
@@ -1,3 +1,5 @@
+use std::net::IpAddr;
+
 use ion::Heap;
 use ion::{class::Reflector, ClassDefinition, Context, Promise};
 use mozjs::jsapi::JSObject;
@@ -7,17 +9,18 @@ pub struct FetchEvent {
     reflector: Reflector,
     pub(crate) request: Heap<*mut JSObject>,
     pub(crate) response: Option<Heap<*mut JSObject>>,
+    #[trace(no_trace)]
+    client_address: IpAddr,
 }
 
 impl FetchEvent {
-    pub fn try_new(cx: &Context, request: super::super::Request) -> anyhow::Result<Self> {
-        let request = Heap::new(super::super::build_fetch_request(cx, request)?);
-
-        Ok(Self {
+    pub fn new(request: *mut JSObject, client_address: IpAddr) -> Self {
+        Self {
             reflector: Default::default(),
-            request,
+            request: Heap::new(request),
             response: None,
-        })
+            client_address,
+        }
     }
 }
 
@@ -36,6 +39,11 @@ impl FetchEvent {
         self.request.get()
     }
 
+    #[ion(get)]
+    pub fn get_client_address(&self) -> String {
+        self.client_address.to_string()
+    }
+
     #[ion(name = "respondWith")]
     pub fn respond_with(&mut self, cx: &Context, response: ion::Value) -> ion::Result<()> {
         match self.response {
@@ -1,5 +1,8 @@
+use std::net::IpAddr;
+
 use anyhow::{anyhow, bail};
 use ion::{conversions::ToValue, ClassDefinition, Context, Object, Promise};
+use mozjs::jsapi::JSObject;
 
 use crate::sm_utils::error_report_to_anyhow_error;
 
@@ -15,10 +18,22 @@ pub fn define(cx: &Context, global: &Object) -> bool {
 pub fn start_request(
     cx: &Context,
     request: Request,
+) -> anyhow::Result<Either<PendingResponse, ReadyResponse>> {
+    let client_address = request.remote_addr.ip();
+    let request = super::build_fetch_request(cx, request)?;
+    dispatch_fetch_event(cx, request, client_address)
+}
+
+/// Invokes the fetch event listener with an already constructed request
+/// object.
+pub fn dispatch_fetch_event(
+    cx: &Context,
+    request: *mut JSObject,
+    client_address: IpAddr,
 ) -> anyhow::Result<Either<PendingResponse, ReadyResponse>> {
     let fetch_event = Object::from(cx.root(fetch_event::FetchEvent::new_object(
         cx,
-        Box::new(fetch_event::FetchEvent::try_new(cx, request)?),
+        Box::new(fetch_event::FetchEvent::new(request, client_address)),
     )));
 
     let callback_rval =
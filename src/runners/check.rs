@@ -0,0 +1,49 @@
+//! Evaluates the user code the same way a worker does before it starts
+//! taking requests, to find errors in it without starting a server.
+
+use anyhow::{Context, Result};
+use std::sync::Arc;
+
+use tokio::{
+    sync::{mpsc, watch},
+    task::LocalSet,
+};
+
+use crate::request_handlers::{RequestHandler, UserCode};
+
+use super::{
+    limits::{RequestLimits, Watchdog},
+    request_loop::{
+        handle_requests, wait_until_initialized, ControlMessage, RuntimeConfig, WorkerState,
+    },
+};
+
+pub fn check_code(handler: impl RequestHandler + Copy + Unpin, user_code: UserCode) -> Result<()> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let (state_tx, mut state_rx) = watch::channel(WorkerState::Starting);
+
+    // There won't be any requests, so the worker can quit as soon as the
+    // code is evaluated and any promises it created are resolved.
+    _ = tx.send(ControlMessage::Shutdown);
+
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed building the Runtime")?
+        .block_on(async move {
+            let local_set = LocalSet::new();
+            local_set
+                .run_until(handle_requests(
+                    handler,
+                    user_code,
+                    rx,
+                    1,
+                    state_tx,
+                    Arc::new(Watchdog::new(RequestLimits::default())),
+                    RuntimeConfig::default(),
+                ))
+                .await;
+            wait_until_initialized(&mut state_rx).await
+        })
+        .context("Failed to evaluate the code")
+}
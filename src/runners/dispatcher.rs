@@ -0,0 +1,657 @@
+//! Picks the worker thread each request is sent to. The threads requests
+//! may go to are published by the runner whenever they change, so requests
+//! can be dispatched without taking the runner's lock. Only when a new
+//! thread should be spawned does the runner need to get involved.
+//!
+//! Requests can also be routed by an affinity key, so the same client ends
+//! up on the same thread and sees the same in-memory state. Keys are mapped
+//! onto threads with rendezvous hashing: every thread gets a score for the
+//! key, and the highest score wins. When threads are started or stopped,
+//! only the keys of the threads that came or went move elsewhere.
+//!
+//! When the number of in-flight requests per thread is limited and all
+//! threads are at the limit, requests wait in a queue until a thread has
+//! room again. Once the queue is full too, requests are shed.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{bail, Context};
+use arc_swap::ArcSwap;
+use rand::Rng;
+use tokio::sync::{mpsc, watch, Notify};
+
+use crate::metrics::WorkerCounters;
+
+use super::{
+    limits::Watchdog,
+    request_loop::{ControlMessage, WorkerState},
+    single::WorkerPoolConfig,
+};
+
+/// How requests are spread over the worker threads, and when new ones are
+/// spawned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SchedulingPolicy {
+    /// Use the first idle thread, spawning a new one if there are none.
+    /// Once all threads have been spawned, use the one with the fewest
+    /// in-flight requests.
+    LeastInFlight,
+    /// Take turns between the threads, spawning a new one when none of them
+    /// are idle.
+    RoundRobin,
+    /// Pick two threads at random and use the one with the fewest in-flight
+    /// requests, spawning a new one when none of them are idle. This spreads
+    /// load almost as well as least-in-flight, without all concurrent
+    /// requests piling onto the same thread.
+    PowerOfTwoChoices,
+    /// Like least-in-flight, but only spawn a new thread once every thread
+    /// has more than the given number of in-flight requests.
+    SpawnAbove(u32),
+}
+
+impl SchedulingPolicy {
+    /// New threads are spawned when every thread has more in-flight requests
+    /// than this.
+    fn spawn_threshold(self) -> i32 {
+        match self {
+            Self::SpawnAbove(n) => n.try_into().unwrap_or(i32::MAX),
+            _ => 0,
+        }
+    }
+}
+
+impl FromStr for SchedulingPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "least-in-flight" => Ok(Self::LeastInFlight),
+            "round-robin" => Ok(Self::RoundRobin),
+            "power-of-two-choices" => Ok(Self::PowerOfTwoChoices),
+            _ => match s.strip_prefix("spawn-above:") {
+                Some(n) => Ok(Self::SpawnAbove(
+                    n.parse()
+                        .with_context(|| format!("Invalid request count '{n}'"))?,
+                )),
+                None => bail!(
+                    "Unknown scheduling policy '{s}', expected least-in-flight, \
+                    round-robin, power-of-two-choices or spawn-above:<requests>"
+                ),
+            },
+        }
+    }
+}
+
+/// What requests are routed to a consistent thread by.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AffinityKey {
+    Header(http::HeaderName),
+    Cookie(String),
+    ClientIp,
+}
+
+impl AffinityKey {
+    /// Hashes the key of the request, if it has one.
+    pub fn hash(&self, addr: SocketAddr, req: &http::request::Parts) -> Option<u64> {
+        let mut hasher = DefaultHasher::new();
+        match self {
+            Self::Header(name) => req.headers.get(name)?.as_bytes().hash(&mut hasher),
+            Self::Cookie(name) => req
+                .headers
+                .get_all(http::header::COOKIE)
+                .iter()
+                .filter_map(|h| h.to_str().ok())
+                .flat_map(|h| h.split(';'))
+                .find_map(|c| c.trim().strip_prefix(name.as_str())?.strip_prefix('='))?
+                .hash(&mut hasher),
+            Self::ClientIp => addr.ip().hash(&mut hasher),
+        }
+        Some(hasher.finish())
+    }
+}
+
+impl FromStr for AffinityKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "client-ip" {
+            Ok(Self::ClientIp)
+        } else if let Some(name) = s.strip_prefix("header:") {
+            Ok(Self::Header(name.parse().with_context(|| {
+                format!("Invalid header name '{name}'")
+            })?))
+        } else if let Some(name) = s.strip_prefix("cookie:").filter(|n| !n.is_empty()) {
+            Ok(Self::Cookie(name.to_owned()))
+        } else {
+            bail!("Invalid affinity key '{s}', expected header:<name>, cookie:<name> or client-ip")
+        }
+    }
+}
+
+/// The parts of a worker thread needed to send requests to it.
+pub struct WorkerHandle {
+    pub index: usize,
+    pub channel: mpsc::UnboundedSender<ControlMessage>,
+    pub counters: Arc<WorkerCounters>,
+    pub state: watch::Receiver<WorkerState>,
+    pub watchdog: Arc<Watchdog>,
+}
+
+impl WorkerHandle {
+    pub fn in_flight_requests(&self) -> i32 {
+        self.counters.in_flight_requests.load(Ordering::SeqCst)
+    }
+
+    /// Whether the thread can take requests. The runner stops publishing
+    /// threads that failed, but may not have noticed yet.
+    fn is_available(&self) -> bool {
+        !self.channel.is_closed()
+            && !matches!(*self.state.borrow(), WorkerState::Failed(_))
+            && !self.watchdog.is_unresponsive()
+    }
+}
+
+pub enum Dispatch {
+    Worker(Arc<WorkerHandle>),
+    /// All threads are busy, and there's room for another one.
+    Spawn,
+    /// All threads are at the in-flight request limit.
+    Full,
+    /// There are no threads that can take requests.
+    Unavailable,
+}
+
+pub struct Dispatcher {
+    policy: SchedulingPolicy,
+    affinity: Option<AffinityKey>,
+    max_threads: usize,
+    max_in_flight_per_thread: Option<u32>,
+    max_queued_requests: Option<usize>,
+    workers: ArcSwap<Vec<Arc<WorkerHandle>>>,
+    round_robin: AtomicUsize,
+    queued_requests: AtomicUsize,
+    /// Notified when a thread may have room for another request.
+    room_freed: Notify,
+}
+
+/// A request waiting in the queue. It leaves the queue when dropped.
+pub struct QueuedRequest<'a>(&'a Dispatcher);
+
+impl Drop for QueuedRequest<'_> {
+    fn drop(&mut self) {
+        self.0.queued_requests.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Dispatcher {
+    pub fn new(config: &WorkerPoolConfig) -> Self {
+        Self {
+            policy: config.policy,
+            affinity: config.affinity.clone(),
+            max_threads: config.max_threads,
+            max_in_flight_per_thread: config.max_in_flight_per_thread,
+            max_queued_requests: config.max_queued_requests,
+            workers: ArcSwap::from_pointee(vec![]),
+            round_robin: AtomicUsize::new(0),
+            queued_requests: AtomicUsize::new(0),
+            room_freed: Notify::new(),
+        }
+    }
+
+    /// Replaces the threads requests are sent to.
+    pub fn publish(&self, workers: Vec<Arc<WorkerHandle>>) {
+        self.workers.store(Arc::new(workers));
+        self.room_freed.notify_waiters();
+    }
+
+    /// Lets queued requests know a request finished, so its thread may have
+    /// room for them.
+    pub fn request_finished(&self) {
+        if self.max_in_flight_per_thread.is_some() {
+            self.room_freed.notify_waiters();
+        }
+    }
+
+    /// Resolves when a thread may have room for another request. To not
+    /// miss a notification, this should be created and enabled before
+    /// looking for a thread.
+    pub fn room_freed(&self) -> tokio::sync::futures::Notified<'_> {
+        self.room_freed.notified()
+    }
+
+    /// Puts a request in the queue, or returns `None` if it's full and the
+    /// request should be shed.
+    pub fn enqueue(&self) -> Option<QueuedRequest<'_>> {
+        let queued = QueuedRequest(self);
+        let ahead = self.queued_requests.fetch_add(1, Ordering::SeqCst);
+        match self.max_queued_requests {
+            Some(max) if ahead >= max => None,
+            _ => Some(queued),
+        }
+    }
+
+    fn max_in_flight_requests(&self) -> i32 {
+        self.max_in_flight_per_thread
+            .map_or(i32::MAX, |max| max.try_into().unwrap_or(i32::MAX))
+    }
+
+    fn has_room(&self, worker: &WorkerHandle) -> bool {
+        worker.in_flight_requests() < self.max_in_flight_requests()
+    }
+
+    /// Counts a request against the in-flight requests of a thread, unless
+    /// another request took the last spot on it since it was chosen.
+    pub fn acquire(self: &Arc<Self>, worker: &WorkerHandle) -> Option<InFlightRequest> {
+        let max = self.max_in_flight_requests();
+        worker
+            .counters
+            .in_flight_requests
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .ok()?;
+        worker
+            .counters
+            .dispatched_requests
+            .fetch_add(1, Ordering::SeqCst);
+
+        Some(InFlightRequest {
+            counters: worker.counters.clone(),
+            dispatcher: self.clone(),
+        })
+    }
+
+    /// Picks the thread for a request by its affinity key. As long as the
+    /// same threads are available, requests with the same key always go to
+    /// the same thread. Returns `None` if affinity routing is disabled, the
+    /// request has no key or there are no threads, in which case it's up to
+    /// the scheduling policy.
+    pub fn choose_by_affinity(
+        &self,
+        addr: SocketAddr,
+        req: &http::request::Parts,
+    ) -> Option<Dispatch> {
+        let key = self.affinity.as_ref()?.hash(addr, req)?;
+        let workers = self.workers.load();
+        let worker = workers
+            .iter()
+            .filter(|w| w.is_available())
+            .max_by_key(|w| {
+                let mut hasher = DefaultHasher::new();
+                (key, w.index).hash(&mut hasher);
+                hasher.finish()
+            })?;
+
+        if self.has_room(worker) {
+            Some(Dispatch::Worker(worker.clone()))
+        } else {
+            Some(Dispatch::Full)
+        }
+    }
+
+    /// Picks a thread for the next request. If `may_spawn` is false, an
+    /// existing thread is picked even if the policy would rather spawn one.
+    pub fn choose(&self, may_spawn: bool) -> Dispatch {
+        let workers = self.workers.load();
+        let available = workers
+            .iter()
+            .filter(|w| w.is_available())
+            .collect::<Vec<_>>();
+
+        let threshold = self.policy.spawn_threshold();
+        if may_spawn
+            && workers.len() < self.max_threads
+            && available
+                .iter()
+                .all(|w| w.in_flight_requests() > threshold || !self.has_room(w))
+        {
+            return Dispatch::Spawn;
+        }
+
+        let has_threads = !available.is_empty();
+        let available = available
+            .into_iter()
+            .filter(|w| self.has_room(w))
+            .collect::<Vec<_>>();
+
+        let chosen = match self.policy {
+            SchedulingPolicy::LeastInFlight | SchedulingPolicy::SpawnAbove(_) => {
+                least_in_flight(available.iter().copied())
+            }
+            SchedulingPolicy::RoundRobin if !available.is_empty() => {
+                let next = self.round_robin.fetch_add(1, Ordering::Relaxed);
+                Some(available[next % available.len()])
+            }
+            SchedulingPolicy::PowerOfTwoChoices if available.len() > 1 => {
+                let mut rng = rand::thread_rng();
+                let first = rng.gen_range(0..available.len());
+                let mut second = rng.gen_range(0..available.len() - 1);
+                if second >= first {
+                    second += 1;
+                }
+                least_in_flight([available[first], available[second]].into_iter())
+            }
+            SchedulingPolicy::RoundRobin | SchedulingPolicy::PowerOfTwoChoices => {
+                available.first().copied()
+            }
+        };
+
+        match chosen {
+            Some(worker) => Dispatch::Worker(worker.clone()),
+            None if has_threads => Dispatch::Full,
+            None => Dispatch::Unavailable,
+        }
+    }
+}
+
+/// Returns the first of the threads with the fewest in-flight requests, so
+/// the threads at the end of the list are the first to go idle.
+fn least_in_flight<'a>(
+    workers: impl Iterator<Item = &'a Arc<WorkerHandle>>,
+) -> Option<&'a Arc<WorkerHandle>> {
+    workers.fold(None, |best, w| match best {
+        Some(best) if best.in_flight_requests() <= w.in_flight_requests() => Some(best),
+        _ => Some(w),
+    })
+}
+
+/// A request sent to a thread. It stops counting against the thread's
+/// in-flight requests when dropped.
+pub struct InFlightRequest {
+    counters: Arc<WorkerCounters>,
+    dispatcher: Arc<Dispatcher>,
+}
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        self.counters
+            .in_flight_requests
+            .fetch_sub(1, Ordering::SeqCst);
+        self.dispatcher.request_finished();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::runners::limits::RequestLimits;
+
+    #[test]
+    fn parses_scheduling_policies() {
+        let parse = |s: &str| s.parse::<SchedulingPolicy>().ok();
+        assert_eq!(
+            parse("least-in-flight"),
+            Some(SchedulingPolicy::LeastInFlight)
+        );
+        assert_eq!(parse("round-robin"), Some(SchedulingPolicy::RoundRobin));
+        assert_eq!(
+            parse("power-of-two-choices"),
+            Some(SchedulingPolicy::PowerOfTwoChoices)
+        );
+        assert_eq!(
+            parse("spawn-above:4"),
+            Some(SchedulingPolicy::SpawnAbove(4))
+        );
+        assert_eq!(parse("spawn-above:"), None);
+        assert_eq!(parse("spawn-above:-1"), None);
+        assert_eq!(parse("fastest"), None);
+    }
+
+    #[test]
+    fn parses_affinity_keys() {
+        let parse = |s: &str| s.parse::<AffinityKey>().ok();
+        assert_eq!(parse("client-ip"), Some(AffinityKey::ClientIp));
+        assert_eq!(
+            parse("header:X-User"),
+            Some(AffinityKey::Header(http::HeaderName::from_static("x-user")))
+        );
+        assert_eq!(
+            parse("cookie:session"),
+            Some(AffinityKey::Cookie("session".into()))
+        );
+        assert_eq!(parse("header:not a header"), None);
+        assert_eq!(parse("cookie:"), None);
+        assert_eq!(parse("ip"), None);
+    }
+
+    fn request(headers: &[(&str, &str)]) -> http::request::Parts {
+        let mut req = http::Request::builder();
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(()).unwrap().into_parts().0
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn hashes_affinity_keys() {
+        let client = addr("192.0.2.1:1234");
+
+        let header = AffinityKey::Header(http::HeaderName::from_static("x-user"));
+        let alice = header.hash(client, &request(&[("x-user", "alice")]));
+        assert!(alice.is_some());
+        assert_eq!(
+            header.hash(addr("192.0.2.2:80"), &request(&[("x-user", "alice")])),
+            alice
+        );
+        assert_ne!(header.hash(client, &request(&[("x-user", "bob")])), alice);
+        assert_eq!(header.hash(client, &request(&[])), None);
+
+        let cookie = AffinityKey::Cookie("session".into());
+        let session = cookie.hash(client, &request(&[("cookie", "session=abc")]));
+        assert!(session.is_some());
+        assert_eq!(
+            cookie.hash(
+                client,
+                &request(&[("cookie", "theme=dark"), ("cookie", "a=1; session=abc")])
+            ),
+            session
+        );
+        assert_eq!(
+            cookie.hash(client, &request(&[("cookie", "sessionid=abc")])),
+            None
+        );
+
+        let client_ip = AffinityKey::ClientIp;
+        assert_eq!(
+            client_ip.hash(client, &request(&[])),
+            client_ip.hash(addr("192.0.2.1:5678"), &request(&[]))
+        );
+        assert_ne!(
+            client_ip.hash(client, &request(&[])),
+            client_ip.hash(addr("192.0.2.2:1234"), &request(&[]))
+        );
+    }
+
+    struct TestWorker {
+        handle: Arc<WorkerHandle>,
+        // Keeps the channel open, so the worker counts as available
+        _channel: mpsc::UnboundedReceiver<ControlMessage>,
+    }
+
+    fn worker(index: usize, in_flight: i32) -> TestWorker {
+        let (channel, receiver) = mpsc::unbounded_channel();
+        let counters = Arc::new(WorkerCounters::default());
+        counters
+            .in_flight_requests
+            .store(in_flight, Ordering::SeqCst);
+        TestWorker {
+            handle: Arc::new(WorkerHandle {
+                index,
+                channel,
+                counters,
+                state: watch::channel(WorkerState::Ready).1,
+                watchdog: Arc::new(Watchdog::new(RequestLimits::default())),
+            }),
+            _channel: receiver,
+        }
+    }
+
+    fn new_dispatcher(
+        policy: SchedulingPolicy,
+        max_threads: usize,
+        max_in_flight_per_thread: Option<u32>,
+        workers: &[TestWorker],
+    ) -> Dispatcher {
+        let dispatcher = Dispatcher {
+            policy,
+            affinity: Some(AffinityKey::Header(http::HeaderName::from_static("x-user"))),
+            max_threads,
+            max_in_flight_per_thread,
+            max_queued_requests: None,
+            workers: ArcSwap::from_pointee(vec![]),
+            round_robin: AtomicUsize::new(0),
+            queued_requests: AtomicUsize::new(0),
+            room_freed: Notify::new(),
+        };
+        dispatcher.publish(workers.iter().map(|w| w.handle.clone()).collect());
+        dispatcher
+    }
+
+    fn chosen(dispatch: Dispatch) -> Option<usize> {
+        match dispatch {
+            Dispatch::Worker(worker) => Some(worker.index),
+            _ => None,
+        }
+    }
+
+    fn choose_for(dispatcher: &Dispatcher, user: &str) -> Option<usize> {
+        let req = request(&[("x-user", user)]);
+        chosen(dispatcher.choose_by_affinity(addr("192.0.2.1:1234"), &req)?)
+    }
+
+    #[test]
+    fn keeps_affinity_keys_on_the_same_thread() {
+        let workers = (0..4).map(|i| worker(i, 0)).collect::<Vec<_>>();
+        let dispatcher = new_dispatcher(SchedulingPolicy::LeastInFlight, 4, None, &workers);
+        let users = (0..64).map(|i| format!("user-{i}")).collect::<Vec<_>>();
+
+        let before = users
+            .iter()
+            .map(|user| choose_for(&dispatcher, user).unwrap())
+            .collect::<Vec<_>>();
+        for (user, index) in users.iter().zip(&before) {
+            assert_eq!(choose_for(&dispatcher, user), Some(*index));
+        }
+        // Keys are spread over all threads
+        for index in 0..4 {
+            assert!(before.contains(&index), "no keys on thread {index}");
+        }
+
+        // Only the keys of a thread that goes away move elsewhere
+        dispatcher.publish(
+            workers
+                .iter()
+                .filter(|w| w.handle.index != 2)
+                .map(|w| w.handle.clone())
+                .collect(),
+        );
+        for (user, index) in users.iter().zip(&before) {
+            let after = choose_for(&dispatcher, user).unwrap();
+            if *index == 2 {
+                assert_ne!(after, 2);
+            } else {
+                assert_eq!(after, *index);
+            }
+        }
+
+        // Requests without the key are left to the scheduling policy
+        let req = request(&[]);
+        assert!(dispatcher
+            .choose_by_affinity(addr("192.0.2.1:1234"), &req)
+            .is_none());
+    }
+
+    #[test]
+    fn reports_full_threads_for_affinity_keys() {
+        let workers = [worker(0, 1)];
+        let dispatcher = new_dispatcher(SchedulingPolicy::LeastInFlight, 1, Some(1), &workers);
+        let req = request(&[("x-user", "alice")]);
+        assert!(matches!(
+            dispatcher.choose_by_affinity(addr("192.0.2.1:1234"), &req),
+            Some(Dispatch::Full)
+        ));
+    }
+
+    #[test]
+    fn chooses_least_in_flight() {
+        let workers = [worker(0, 2), worker(1, 1), worker(2, 1)];
+        let dispatcher = new_dispatcher(SchedulingPolicy::LeastInFlight, 3, None, &workers);
+        assert_eq!(chosen(dispatcher.choose(true)), Some(1));
+
+        // Idle threads are used before spawning new ones
+        let workers = [worker(0, 1), worker(1, 0)];
+        let dispatcher = new_dispatcher(SchedulingPolicy::LeastInFlight, 3, None, &workers);
+        assert_eq!(chosen(dispatcher.choose(true)), Some(1));
+    }
+
+    #[test]
+    fn spawns_when_all_threads_are_busy() {
+        let workers = [worker(0, 1), worker(1, 2)];
+        let dispatcher = new_dispatcher(SchedulingPolicy::LeastInFlight, 3, None, &workers);
+        assert!(matches!(dispatcher.choose(true), Dispatch::Spawn));
+        assert_eq!(chosen(dispatcher.choose(false)), Some(0));
+
+        // Not beyond the maximum though
+        let dispatcher = new_dispatcher(SchedulingPolicy::LeastInFlight, 2, None, &workers);
+        assert_eq!(chosen(dispatcher.choose(true)), Some(0));
+
+        // And only above the threshold with spawn-above
+        let policy = SchedulingPolicy::SpawnAbove(1);
+        let dispatcher = new_dispatcher(policy, 3, None, &workers);
+        assert_eq!(chosen(dispatcher.choose(true)), Some(0));
+        let workers = [worker(0, 2), worker(1, 2)];
+        let dispatcher = new_dispatcher(policy, 3, None, &workers);
+        assert!(matches!(dispatcher.choose(true), Dispatch::Spawn));
+    }
+
+    #[test]
+    fn takes_turns_with_round_robin() {
+        let workers = [worker(0, 1), worker(1, 1), worker(2, 1)];
+        let dispatcher = new_dispatcher(SchedulingPolicy::RoundRobin, 3, None, &workers);
+        let chosen = (0..6)
+            .map(|_| chosen(dispatcher.choose(true)).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(chosen, [0, 1, 2, 0, 1, 2]);
+    }
+
+    #[test]
+    fn picks_the_less_busy_of_two_choices() {
+        // With three threads, any two random picks include one of the idle
+        // threads
+        let workers = [worker(0, 5), worker(1, 0), worker(2, 0)];
+        let dispatcher = new_dispatcher(SchedulingPolicy::PowerOfTwoChoices, 3, None, &workers);
+        for _ in 0..32 {
+            assert_ne!(chosen(dispatcher.choose(false)), Some(0));
+        }
+    }
+
+    #[test]
+    fn respects_the_in_flight_limit() {
+        let workers = [worker(0, 2), worker(1, 1)];
+        let dispatcher = new_dispatcher(SchedulingPolicy::RoundRobin, 2, Some(2), &workers);
+        for _ in 0..4 {
+            assert_eq!(chosen(dispatcher.choose(true)), Some(1));
+        }
+
+        let workers = [worker(0, 2), worker(1, 2)];
+        let dispatcher = new_dispatcher(SchedulingPolicy::LeastInFlight, 2, Some(2), &workers);
+        assert!(matches!(dispatcher.choose(true), Dispatch::Full));
+
+        let dispatcher = new_dispatcher(SchedulingPolicy::LeastInFlight, 2, Some(2), &[]);
+        assert!(matches!(dispatcher.choose(false), Dispatch::Unavailable));
+    }
+}
@@ -5,17 +5,24 @@ use tokio::task::LocalSet;
 
 use crate::{
     builtins,
-    sm_utils::{error_report_option_to_anyhow_error, evaluate_module, evaluate_script, JsApp},
+    runners::module_loader::CachingLoader,
+    sm_utils::{
+        error_report_option_to_anyhow_error, evaluate_module, evaluate_script, HeapConfig, JsApp,
+    },
 };
 
 async fn exec_script_inner(path: impl AsRef<Path>, script_mode: bool) -> Result<()> {
-    let module_loader = (!script_mode).then(runtime::module::Loader::default);
+    let module_loader = (!script_mode).then(CachingLoader::<runtime::module::Loader>::default);
     let standard_modules = builtins::Modules {
         include_internal: !script_mode,
         hardware_concurrency: 1,
     };
 
-    let js_app = JsApp::build(module_loader, Some(standard_modules));
+    let js_app = JsApp::build(
+        module_loader,
+        Some(standard_modules),
+        &HeapConfig::default(),
+    );
     let cx = js_app.cx();
     let rt = js_app.rt();
 
@@ -10,21 +10,30 @@ use std::{
     time::{Duration, Instant},
 };
 
-use anyhow::anyhow;
+use anyhow::{anyhow, bail, Context};
 use async_trait::async_trait;
 use futures::Future;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 
-use crate::request_handlers::{RequestHandler, UserCode};
+use crate::{
+    metrics::METRICS,
+    request_handlers::{RequestHandler, UserCode},
+};
 
 use super::{
-    request_loop::{handle_requests, ControlMessage, RequestData},
+    limits::{RequestLimits, Watchdog},
+    request_loop::{
+        handle_requests, wait_until_initialized, ControlMessage, RequestData, RuntimeConfig,
+        WorkerState,
+    },
     ResponseData,
 };
 
 #[derive(Clone)]
 pub struct InlineRunner {
     channel: mpsc::UnboundedSender<ControlMessage>,
+    state: watch::Receiver<WorkerState>,
+    shutting_down: Arc<AtomicBool>,
     finished: Arc<AtomicBool>,
 }
 
@@ -36,35 +45,49 @@ impl InlineRunner {
     pub fn new_request_handler(
         handler: impl RequestHandler + Copy + Unpin,
         user_code: UserCode,
+        limits: RequestLimits,
+        runtime: RuntimeConfig,
     ) -> (Self, impl InlineRunnerRequestHandlerFuture) {
         let (tx, rx) = mpsc::unbounded_channel();
+        let (state_tx, state_rx) = watch::channel(WorkerState::Starting);
         let this = Self {
             channel: tx,
+            state: state_rx,
+            shutting_down: Arc::new(AtomicBool::new(false)),
             finished: Arc::new(AtomicBool::new(false)),
         };
         let finished_clone = this.finished.clone();
         let fut = async move {
-            handle_requests(handler, user_code, rx, 1).await;
+            let watchdog = Arc::new(Watchdog::new(limits));
+            handle_requests(handler, user_code, rx, 1, state_tx, watchdog, runtime).await;
             // Remember, we're running single-threaded, so no need
             // for any specific ordering logic.
             finished_clone.store(true, Ordering::Relaxed);
         };
         (this, fut)
     }
+
+    /// Waits for the code to be evaluated, which happens once the request
+    /// handler future starts running, and returns the error if that failed.
+    pub async fn wait_until_started(&self) -> anyhow::Result<()> {
+        wait_until_initialized(&mut self.state.clone())
+            .await
+            .context("Failed to evaluate the code")
+    }
 }
 
 #[async_trait]
 impl crate::server::Runner for InlineRunner {
     async fn handle(
         &self,
-        _addr: std::net::SocketAddr,
+        addr: std::net::SocketAddr,
         req: http::request::Parts,
         body: hyper::Body,
     ) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
         let (tx, rx) = tokio::sync::oneshot::channel();
 
         self.channel.send(ControlMessage::HandleRequest(
-            RequestData { _addr, req, body },
+            RequestData { addr, req, body },
             tx,
         ))?;
 
@@ -72,9 +95,13 @@ impl crate::server::Runner for InlineRunner {
 
         // TODO: handle script errors
         match response {
-            ResponseData::Done(resp) => Ok(resp),
+            ResponseData::Done(mut resp) => {
+                resp.extensions_mut().insert(crate::server::WorkerIndex(0));
+                Ok(resp)
+            }
             ResponseData::RequestError(err) => Err(err),
             ResponseData::ScriptError(err) => {
+                METRICS.script_error();
                 if let Some(err) = err {
                     println!("{err:?}");
                 }
@@ -83,15 +110,31 @@ impl crate::server::Runner for InlineRunner {
         }
     }
 
-    async fn shutdown(&self, timeout: Option<Duration>) {
+    async fn status(&self) -> crate::server::RunnerStatus {
+        let alive = !self.finished.load(Ordering::Relaxed);
+        crate::server::RunnerStatus {
+            ready: alive
+                && !self.shutting_down.load(Ordering::Relaxed)
+                && matches!(*self.state.borrow(), WorkerState::Ready),
+            alive,
+        }
+    }
+
+    async fn reload(&self) -> anyhow::Result<()> {
+        bail!("Reloading the code is not supported in single-threaded mode")
+    }
+
+    async fn shutdown(&self, timeout: Option<Duration>) -> crate::server::ShutdownOutcome {
         tracing::info!("Shutting down...");
 
+        self.shutting_down.store(true, Ordering::Relaxed);
         if self.channel.send(ControlMessage::Shutdown).is_err() {
             // Channel already closed, future must have run to completion
-            return;
+            return crate::server::ShutdownOutcome::Completed;
         }
 
         let shutdown_started = Instant::now();
+        let mut outcome = crate::server::ShutdownOutcome::Completed;
 
         loop {
             if !self.finished.load(Ordering::Relaxed) {
@@ -101,6 +144,7 @@ impl crate::server::Runner for InlineRunner {
                             "Clean shutdown timeout was reached before all \
                             requests could finish processing"
                         );
+                        outcome = crate::server::ShutdownOutcome::TimedOut;
                         let _ = self.channel.send(ControlMessage::Terminate);
                         break;
                     }
@@ -117,5 +161,6 @@ impl crate::server::Runner for InlineRunner {
             "Shutdown completed in {} seconds",
             shutdown_started.elapsed().as_secs()
         );
+        outcome
     }
 }
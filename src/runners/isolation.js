@@ -0,0 +1,39 @@
+// Keeps track of the timers set in a request's realm, so the ones still
+// pending once the request is done can be cleared instead of firing while
+// the next request runs.
+(() => {
+  const timers = new Set();
+  const { setTimeout, setInterval, clearTimeout, clearInterval } = globalThis;
+
+  globalThis.setTimeout = function (...args) {
+    const id = setTimeout(...args);
+    timers.add(id);
+    return id;
+  };
+
+  globalThis.setInterval = function (...args) {
+    const id = setInterval(...args);
+    timers.add(id);
+    return id;
+  };
+
+  globalThis.clearTimeout = function (id) {
+    timers.delete(id);
+    clearTimeout(id);
+  };
+
+  globalThis.clearInterval = function (id) {
+    timers.delete(id);
+    clearInterval(id);
+  };
+
+  Object.defineProperty(globalThis, "__winterjs_clear_timers", {
+    value: () => {
+      for (const id of timers) {
+        clearInterval(id);
+      }
+      timers.clear();
+    },
+    enumerable: false,
+  });
+})();
@@ -0,0 +1,773 @@
+//! Per-request wall-clock and CPU time limits.
+//!
+//! JavaScript runs synchronously on a worker thread, so a script stuck in a
+//! loop never gives the request loop a chance to notice that a request is
+//! taking too long. Instead, a watchdog thread keeps asking SpiderMonkey to
+//! call our interrupt callback while the worker is running JavaScript. The
+//! callback runs on the worker thread, and terminates the script if it's
+//! running for a request that's over its limits. Termination can't be
+//! caught by the script, so control returns to the request loop, which
+//! answers the offending requests.
+//!
+//! CPU time is charged to requests as follows: JavaScript run for a specific
+//! request, such as its fetch handler, is charged to that request alone.
+//! Everything else, like promise reactions run by the event loop, can't be
+//! attributed to a request and is split evenly between the requests in
+//! flight. Such a stretch of JavaScript is only terminated once it has used
+//! up a whole request's budget (or timeout) on its own, so other requests
+//! going over their limits in the meantime don't cut it short.
+//!
+//! The limits apply until a response is produced, and not to streaming its
+//! body.
+//!
+//! If the worker doesn't respond to an interrupt for as long as a request
+//! may take at all, e.g. because it's stuck in native code, the watchdog
+//! marks it as unresponsive so it can be replaced.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    future::Future,
+    pin::pin,
+    sync::{Arc, Condvar, Mutex},
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+use mozjs::jsapi::{JSContext, JS_AddInterruptCallback, JS_RequestInterruptCallback};
+use tokio::sync::watch;
+
+/// How long the worker may run JavaScript before the watchdog interrupts it
+/// to check the limits, and how often it does so afterwards.
+const CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Workers are never considered unresponsive sooner than this, so very
+/// short limits don't get workers replaced over a slow garbage collection.
+const MIN_UNRESPONSIVE_AFTER: Duration = Duration::from_secs(1);
+
+/// Limits on the time a single request may take.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RequestLimits {
+    /// Wall-clock time from a worker thread starting to handle a request to
+    /// the response being produced.
+    pub timeout: Option<Duration>,
+    /// CPU time the JavaScript handling a request may use.
+    pub cpu_budget: Option<Duration>,
+}
+
+impl RequestLimits {
+    fn is_unlimited(&self) -> bool {
+        self.timeout.is_none() && self.cpu_budget.is_none()
+    }
+
+    fn unresponsive_after(&self) -> Duration {
+        self.timeout
+            .max(self.cpu_budget)
+            .unwrap_or_default()
+            .max(MIN_UNRESPONSIVE_AFTER)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(super) enum LimitExceeded {
+    Timeout,
+    CpuBudget,
+}
+
+/// Shared between a worker thread and its watchdog thread.
+pub struct Watchdog {
+    limits: RequestLimits,
+    state: Mutex<WatchdogState>,
+    wake: Condvar,
+    unresponsive: watch::Sender<bool>,
+}
+
+struct WatchdogState {
+    /// Set once the worker thread is done, and the watchdog thread should
+    /// quit.
+    stopped: bool,
+    /// The worker's context, set while its JavaScript runtime is alive.
+    cx: Option<ContextPtr>,
+    /// Requests being tracked on the worker.
+    in_flight: usize,
+    /// When the worker started running JavaScript, if it is.
+    running_since: Option<Instant>,
+    /// When an interrupt was requested that the worker hasn't handled yet.
+    interrupt_requested_at: Option<Instant>,
+}
+
+struct ContextPtr(*mut JSContext);
+
+// The watchdog only uses the context to request an interrupt, which is
+// allowed from any thread.
+unsafe impl Send for ContextPtr {}
+
+impl Watchdog {
+    pub fn new(limits: RequestLimits) -> Self {
+        if limits.cpu_budget.is_some() && !*HAS_THREAD_CPU_CLOCK {
+            tracing::warn!(
+                "CPU time can't be measured per thread on this platform, so the CPU budget \
+                 is enforced on wall-clock time instead"
+            );
+        }
+
+        Self {
+            limits,
+            state: Mutex::new(WatchdogState {
+                stopped: false,
+                cx: None,
+                in_flight: 0,
+                running_since: None,
+                interrupt_requested_at: None,
+            }),
+            wake: Condvar::new(),
+            unresponsive: watch::channel(false).0,
+        }
+    }
+
+    /// Whether the worker couldn't be interrupted, and should be replaced.
+    pub fn is_unresponsive(&self) -> bool {
+        *self.unresponsive.borrow()
+    }
+
+    pub async fn wait_until_unresponsive(&self) {
+        _ = self.unresponsive.subscribe().wait_for(|u| *u).await;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, WatchdogState> {
+        self.state.lock().expect("Watchdog state lock was poisoned")
+    }
+
+    fn watch(&self) {
+        let unresponsive_after = self.limits.unresponsive_after();
+        let mut state = self.lock();
+
+        // The worker's runtime may be replaced, but the thread stays the same
+        while !state.stopped {
+            let cx = match &state.cx {
+                Some(cx) if state.in_flight > 0 => cx.0,
+                _ => {
+                    state = self
+                        .wake
+                        .wait(state)
+                        .expect("Watchdog state lock was poisoned");
+                    continue;
+                }
+            };
+
+            if let Some(running_since) = state.running_since {
+                let now = Instant::now();
+                match state.interrupt_requested_at {
+                    Some(requested_at)
+                        if now.duration_since(requested_at) >= unresponsive_after
+                            && !self.is_unresponsive() =>
+                    {
+                        tracing::error!(
+                            "A worker thread has been running Javascript for {} seconds \
+                            without responding to interrupts",
+                            now.duration_since(running_since).as_secs()
+                        );
+                        self.unresponsive.send_replace(true);
+                    }
+                    Some(_) => (),
+                    None if now.duration_since(running_since) >= CHECK_INTERVAL => {
+                        state.interrupt_requested_at = Some(now);
+                        // The lock keeps the worker from destroying the context
+                        // in the meantime
+                        unsafe { JS_RequestInterruptCallback(cx) };
+                    }
+                    None => (),
+                }
+            }
+
+            state = self
+                .wake
+                .wait_timeout(state, CHECK_INTERVAL)
+                .expect("Watchdog state lock was poisoned")
+                .0;
+        }
+    }
+}
+
+thread_local! {
+    static TRACKER: RefCell<Option<Tracker>> = const { RefCell::new(None) };
+
+    /// Set when a request goes over its limits, until the request loop
+    /// picks that up.
+    static LIMIT_EXCEEDED: Cell<bool> = const { Cell::new(false) };
+
+    /// Set when the interrupt callback terminated a script.
+    static TERMINATED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Keeps track of the requests on the current worker thread and the CPU
+/// time they used.
+struct Tracker {
+    watchdog: Arc<Watchdog>,
+    requests: HashMap<u64, TrackedRequest>,
+    next_id: u64,
+    /// The stretch of JavaScript the worker is running, if it is.
+    segment: Option<Segment>,
+}
+
+struct TrackedRequest {
+    deadline: Option<Instant>,
+    cpu_used: Duration,
+    exceeded: Option<LimitExceeded>,
+}
+
+#[derive(Clone, Copy)]
+struct Segment {
+    /// The request the JavaScript is run for, if known.
+    request: Option<u64>,
+    started_at: Instant,
+    cpu_started_at: Duration,
+    /// The CPU time up to which the segment was charged already.
+    cpu_charged_until: Duration,
+}
+
+impl Segment {
+    fn start(request: Option<u64>) -> Self {
+        let cpu = thread_cpu_time();
+        Self {
+            request,
+            started_at: Instant::now(),
+            cpu_started_at: cpu,
+            cpu_charged_until: cpu,
+        }
+    }
+}
+
+impl Tracker {
+    fn exceed(&mut self, id: u64, reason: LimitExceeded) {
+        if let Some(request) = self.requests.get_mut(&id) {
+            if request.exceeded.is_none() {
+                request.exceeded = Some(reason);
+                LIMIT_EXCEEDED.set(true);
+            }
+        }
+    }
+
+    fn charge(&mut self, request: Option<u64>, cpu: Duration) {
+        let charged = match request {
+            Some(id) => vec![id],
+            None => self
+                .requests
+                .iter()
+                .filter(|(_, r)| r.exceeded.is_none())
+                .map(|(id, _)| *id)
+                .collect(),
+        };
+        if charged.is_empty() {
+            return;
+        }
+
+        let share = cpu / charged.len() as u32;
+        for id in charged {
+            let Some(request) = self.requests.get_mut(&id) else {
+                continue;
+            };
+            request.cpu_used += share;
+            let over_budget = self
+                .watchdog
+                .limits
+                .cpu_budget
+                .is_some_and(|budget| request.cpu_used > budget);
+            if over_budget {
+                self.exceed(id, LimitExceeded::CpuBudget);
+            }
+        }
+    }
+
+    /// Charges the current segment for the CPU time it used so far.
+    fn charge_segment(&mut self) {
+        if let Some(mut segment) = self.segment {
+            let cpu = thread_cpu_time();
+            self.charge(segment.request, cpu - segment.cpu_charged_until);
+            segment.cpu_charged_until = cpu;
+            self.segment = Some(segment);
+        }
+    }
+
+    fn check_deadlines(&mut self) {
+        let now = Instant::now();
+        let expired = self
+            .requests
+            .iter()
+            .filter(|(_, r)| r.exceeded.is_none() && r.deadline.is_some_and(|d| d <= now))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in expired {
+            self.exceed(id, LimitExceeded::Timeout);
+        }
+    }
+
+    /// Returns false to terminate the running script.
+    fn handle_interrupt(&mut self) -> bool {
+        self.watchdog.lock().interrupt_requested_at = None;
+
+        self.charge_segment();
+        self.check_deadlines();
+
+        let Some(segment) = self.segment else {
+            return true;
+        };
+        let terminate = match segment.request {
+            Some(id) => self.requests.get(&id).is_some_and(|r| r.exceeded.is_some()),
+            None => {
+                let limits = self.watchdog.limits;
+                let ran_too_long = limits
+                    .timeout
+                    .is_some_and(|t| segment.started_at.elapsed() >= t)
+                    || limits
+                        .cpu_budget
+                        .is_some_and(|b| segment.cpu_charged_until - segment.cpu_started_at >= b);
+                ran_too_long && self.requests.values().any(|r| r.exceeded.is_some())
+            }
+        };
+
+        if terminate {
+            TERMINATED.set(true);
+            // Whatever runs next gets a fresh start
+            self.segment = Some(Segment::start(segment.request));
+        }
+        !terminate
+    }
+
+    fn begin_turn(&mut self) {
+        self.segment = Some(Segment::start(None));
+        self.watchdog.lock().running_since = Some(Instant::now());
+    }
+
+    fn end_turn(&mut self) {
+        self.charge_segment();
+        self.segment = None;
+        let mut state = self.watchdog.lock();
+        state.running_since = None;
+        state.interrupt_requested_at = None;
+    }
+}
+
+unsafe extern "C" fn interrupt_callback(_cx: *mut JSContext) -> bool {
+    TRACKER.with(|tracker| match tracker.try_borrow_mut() {
+        Ok(mut tracker) => match tracker.as_mut() {
+            Some(tracker) => tracker.handle_interrupt(),
+            None => true,
+        },
+        Err(_) => true,
+    })
+}
+
+fn with_tracker<T>(f: impl FnOnce(&mut Tracker) -> T) -> Option<T> {
+    TRACKER.with(|tracker| tracker.borrow_mut().as_mut().map(f))
+}
+
+/// Whether CPU time can be measured per thread. Without that, CPU budgets
+/// are enforced on wall-clock time instead.
+static HAS_THREAD_CPU_CLOCK: once_cell::sync::Lazy<bool> =
+    once_cell::sync::Lazy::new(|| read_thread_cpu_clock().is_some());
+
+thread_local! {
+    static WALL_CLOCK_EPOCH: Instant = Instant::now();
+    static LAST_CPU_TIME: Cell<Duration> = const { Cell::new(Duration::ZERO) };
+}
+
+#[cfg(not(target_os = "wasi"))]
+fn read_thread_cpu_clock() -> Option<Duration> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    let res = unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) };
+    (res == 0).then(|| Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+#[cfg(target_os = "wasi")]
+fn read_thread_cpu_clock() -> Option<Duration> {
+    None
+}
+
+/// The CPU time used by the current thread, or the time since it first
+/// asked on platforms that can't tell.
+fn thread_cpu_time() -> Duration {
+    if !*HAS_THREAD_CPU_CLOCK {
+        return WALL_CLOCK_EPOCH.with(|epoch| epoch.elapsed());
+    }
+
+    // A failed read counts as no CPU time used since the last one
+    let cpu = read_thread_cpu_clock().unwrap_or_else(|| LAST_CPU_TIME.get());
+    LAST_CPU_TIME.set(cpu);
+    cpu
+}
+
+/// Enforces the limits on the current worker thread until dropped, which
+/// must happen before the context is destroyed.
+pub(super) struct LimitsGuard {
+    watchdog: Arc<Watchdog>,
+}
+
+impl Drop for LimitsGuard {
+    fn drop(&mut self) {
+        TRACKER.with(|tracker| tracker.borrow_mut().take());
+        self.watchdog.lock().cx = None;
+        self.watchdog.wake.notify_all();
+    }
+}
+
+/// Runs the watchdog thread until dropped, which should happen once the
+/// worker thread is done.
+pub(super) struct WatchdogThread {
+    watchdog: Arc<Watchdog>,
+}
+
+impl Drop for WatchdogThread {
+    fn drop(&mut self) {
+        self.watchdog.lock().stopped = true;
+        self.watchdog.wake.notify_all();
+    }
+}
+
+/// Starts the watchdog thread of a worker thread, unless there are no
+/// limits to enforce. It serves every runtime the worker thread goes
+/// through.
+pub(super) fn start_watchdog(watchdog: &Arc<Watchdog>) -> Option<WatchdogThread> {
+    if watchdog.limits.is_unlimited() {
+        return None;
+    }
+
+    let watchdog_clone = watchdog.clone();
+    std::thread::spawn(move || watchdog_clone.watch());
+
+    Some(WatchdogThread {
+        watchdog: watchdog.clone(),
+    })
+}
+
+/// Starts enforcing the watchdog's limits on the current thread's runtime,
+/// unless there are none. The watchdog thread must have been started.
+pub(super) fn install(watchdog: &Arc<Watchdog>, cx: &ion::Context) -> Option<LimitsGuard> {
+    if watchdog.limits.is_unlimited() {
+        return None;
+    }
+
+    unsafe { JS_AddInterruptCallback(cx.as_ptr(), Some(interrupt_callback)) };
+    TRACKER.with(|tracker| {
+        *tracker.borrow_mut() = Some(Tracker {
+            watchdog: watchdog.clone(),
+            requests: HashMap::new(),
+            next_id: 0,
+            segment: None,
+        })
+    });
+    watchdog.lock().cx = Some(ContextPtr(cx.as_ptr()));
+    watchdog.wake.notify_all();
+
+    Some(LimitsGuard {
+        watchdog: watchdog.clone(),
+    })
+}
+
+/// A request whose time is being tracked, until it's dropped.
+pub(super) struct LimitedRequest {
+    id: Option<u64>,
+}
+
+impl LimitedRequest {
+    /// Runs JavaScript on behalf of this request, so its CPU time is
+    /// charged to it alone.
+    pub(super) fn run<T>(&self, f: impl FnOnce() -> T) -> T {
+        let Some(id) = self.id else {
+            return f();
+        };
+
+        let previous = with_tracker(|t| {
+            t.charge_segment();
+            t.segment.replace(Segment::start(Some(id)))
+        })
+        .flatten();
+        let result = f();
+        with_tracker(|t| {
+            t.charge_segment();
+            t.segment = previous.map(|_| Segment::start(None));
+        });
+        result
+    }
+
+    pub(super) fn exceeded(&self) -> Option<LimitExceeded> {
+        let id = self.id?;
+        with_tracker(|t| t.requests.get(&id).and_then(|r| r.exceeded)).flatten()
+    }
+}
+
+impl Drop for LimitedRequest {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            with_tracker(|t| {
+                if t.requests.remove(&id).is_some() {
+                    t.watchdog.lock().in_flight -= 1;
+                }
+            });
+        }
+    }
+}
+
+pub(super) fn track_request() -> LimitedRequest {
+    let id = with_tracker(|t| {
+        let id = t.next_id;
+        t.next_id += 1;
+        t.requests.insert(
+            id,
+            TrackedRequest {
+                deadline: t.watchdog.limits.timeout.map(|t| Instant::now() + t),
+                cpu_used: Duration::ZERO,
+                exceeded: None,
+            },
+        );
+
+        let mut state = t.watchdog.lock();
+        state.in_flight += 1;
+        if state.in_flight == 1 {
+            t.watchdog.wake.notify_all();
+        }
+        id
+    });
+    LimitedRequest { id }
+}
+
+/// Marks the requests past their deadline as having exceeded their limits.
+pub(super) fn check_deadlines() {
+    with_tracker(Tracker::check_deadlines);
+}
+
+/// Resolves once the earliest deadline of the requests in flight passes.
+pub(super) async fn wait_for_deadline() {
+    let deadline = with_tracker(|t| {
+        t.requests
+            .values()
+            .filter(|r| r.exceeded.is_none())
+            .filter_map(|r| r.deadline)
+            .min()
+    })
+    .flatten();
+
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Resolves once a request went over its limits.
+pub(super) fn wait_for_exceeded() -> impl Future<Output = ()> {
+    std::future::poll_fn(|_| {
+        if LIMIT_EXCEEDED.replace(false) {
+            Poll::Ready(())
+        } else {
+            // `metered` wakes the task when this changes
+            Poll::Pending
+        }
+    })
+}
+
+/// Whether a script was terminated since this was last called.
+pub(super) fn take_terminated() -> bool {
+    TERMINATED.replace(false)
+}
+
+/// Measures the time the worker spends running JavaScript, which it does
+/// whenever `future` is polled.
+pub(super) async fn metered<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    std::future::poll_fn(|wcx| {
+        with_tracker(Tracker::begin_turn);
+        let result = future.as_mut().poll(wcx);
+        with_tracker(Tracker::end_turn);
+
+        if LIMIT_EXCEEDED.get() {
+            wcx.waker().wake_by_ref();
+        }
+        result
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    /// Sets up tracking on the current thread, without a JavaScript runtime.
+    fn install_tracker(limits: RequestLimits) -> Arc<Watchdog> {
+        let watchdog = Arc::new(Watchdog::new(limits));
+        TRACKER.with(|tracker| {
+            *tracker.borrow_mut() = Some(Tracker {
+                watchdog: watchdog.clone(),
+                requests: HashMap::new(),
+                next_id: 0,
+                segment: None,
+            })
+        });
+        LIMIT_EXCEEDED.set(false);
+        TERMINATED.set(false);
+        watchdog
+    }
+
+    fn cpu_used(request: &LimitedRequest) -> Duration {
+        with_tracker(|t| t.requests[&request.id.unwrap()].cpu_used).unwrap()
+    }
+
+    fn charge(request: Option<&LimitedRequest>, cpu: Duration) {
+        with_tracker(|t| t.charge(request.and_then(|r| r.id), cpu));
+    }
+
+    #[test]
+    fn tracks_requests_in_flight() {
+        let watchdog = install_tracker(RequestLimits {
+            timeout: Some(Duration::from_secs(1)),
+            cpu_budget: None,
+        });
+
+        let first = track_request();
+        let second = track_request();
+        assert_ne!(first.id, second.id);
+        assert_eq!(watchdog.lock().in_flight, 2);
+
+        drop(first);
+        assert_eq!(watchdog.lock().in_flight, 1);
+        assert!(second.exceeded().is_none());
+    }
+
+    #[test]
+    fn does_not_track_without_limits() {
+        TRACKER.with(|tracker| tracker.borrow_mut().take());
+        let request = track_request();
+        assert!(request.id.is_none());
+        assert!(request.exceeded().is_none());
+        assert_eq!(request.run(|| 42), 42);
+    }
+
+    #[test]
+    fn charges_cpu_time_to_requests() {
+        install_tracker(RequestLimits {
+            timeout: None,
+            cpu_budget: Some(10 * MS),
+        });
+        let first = track_request();
+        let second = track_request();
+
+        charge(Some(&first), 4 * MS);
+        assert_eq!(cpu_used(&first), 4 * MS);
+        assert_eq!(cpu_used(&second), Duration::ZERO);
+
+        // Time that can't be attributed is split between the requests
+        charge(None, 6 * MS);
+        assert_eq!(cpu_used(&first), 7 * MS);
+        assert_eq!(cpu_used(&second), 3 * MS);
+        assert!(!LIMIT_EXCEEDED.get());
+
+        charge(Some(&first), 4 * MS);
+        assert!(matches!(first.exceeded(), Some(LimitExceeded::CpuBudget)));
+        assert!(second.exceeded().is_none());
+        assert!(LIMIT_EXCEEDED.get());
+
+        // Requests already over their limits aren't charged any further
+        charge(None, 4 * MS);
+        assert_eq!(cpu_used(&first), 11 * MS);
+        assert_eq!(cpu_used(&second), 7 * MS);
+    }
+
+    #[test]
+    fn charges_javascript_run_for_a_request() {
+        install_tracker(RequestLimits {
+            timeout: None,
+            cpu_budget: Some(Duration::from_secs(10)),
+        });
+        let busy = track_request();
+        let idle = track_request();
+
+        busy.run(|| {
+            let started = Instant::now();
+            while started.elapsed() < 20 * MS {
+                std::hint::spin_loop();
+            }
+        });
+
+        assert!(cpu_used(&busy) > Duration::ZERO);
+        assert_eq!(cpu_used(&idle), Duration::ZERO);
+    }
+
+    #[test]
+    fn times_out_requests_past_their_deadline() {
+        install_tracker(RequestLimits {
+            timeout: Some(10 * MS),
+            cpu_budget: None,
+        });
+        let request = track_request();
+
+        check_deadlines();
+        assert!(request.exceeded().is_none());
+
+        std::thread::sleep(15 * MS);
+        let late = track_request();
+        check_deadlines();
+        assert!(matches!(request.exceeded(), Some(LimitExceeded::Timeout)));
+        assert!(late.exceeded().is_none());
+    }
+
+    fn interrupt(watchdog: &Watchdog) -> bool {
+        watchdog.lock().interrupt_requested_at = Some(Instant::now());
+        let keep_running = with_tracker(Tracker::handle_interrupt).unwrap();
+        assert!(watchdog.lock().interrupt_requested_at.is_none());
+        keep_running
+    }
+
+    #[test]
+    fn terminates_javascript_run_for_requests_over_their_limits() {
+        let watchdog = install_tracker(RequestLimits {
+            timeout: None,
+            cpu_budget: Some(10 * MS),
+        });
+        let request = track_request();
+        let other = track_request();
+
+        request.run(|| {
+            assert!(interrupt(&watchdog));
+            assert!(!take_terminated());
+
+            charge(Some(&request), 20 * MS);
+            assert!(!interrupt(&watchdog));
+            assert!(take_terminated());
+        });
+
+        // Other requests are left alone
+        other.run(|| assert!(interrupt(&watchdog)));
+    }
+
+    #[test]
+    fn terminates_unattributed_javascript_that_used_a_whole_budget() {
+        let watchdog = install_tracker(RequestLimits {
+            timeout: Some(10 * MS),
+            cpu_budget: None,
+        });
+        let request = track_request();
+
+        with_tracker(Tracker::begin_turn);
+        assert!(watchdog.lock().running_since.is_some());
+        // Not while no request went over its limits
+        std::thread::sleep(15 * MS);
+        with_tracker(|t| t.requests.values_mut().for_each(|r| r.deadline = None));
+        assert!(interrupt(&watchdog));
+
+        // Nor once a request did, but the JavaScript hasn't run for long
+        with_tracker(|t| t.exceed(request.id.unwrap(), LimitExceeded::Timeout));
+        with_tracker(Tracker::begin_turn);
+        assert!(interrupt(&watchdog));
+
+        std::thread::sleep(15 * MS);
+        assert!(!interrupt(&watchdog));
+        assert!(take_terminated());
+
+        with_tracker(Tracker::end_turn);
+        assert!(watchdog.lock().running_since.is_none());
+    }
+}
@@ -1,11 +1,17 @@
+pub mod check;
+pub mod dispatcher;
 mod event_loop_stream;
 pub mod exec;
 pub mod inline;
+pub mod limits;
+mod module_loader;
 mod request_loop;
 mod request_queue;
 pub mod single;
 pub mod watch;
 
+pub use request_loop::RuntimeConfig;
+
 #[derive(Debug)]
 pub enum ResponseData {
     Done(hyper::Response<hyper::Body>),
@@ -0,0 +1,97 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use ion::{
+    module::{Module, ModuleData, ModuleLoader, ModuleRequest},
+    Context, Error, ErrorKind, Object, PermanentHeap, ResultExc, Value,
+};
+use mozjs::jsapi::JSObject;
+
+use crate::sm_utils;
+
+use super::watch::normalize;
+
+/// Wraps a module loader to load modules imported by path through the
+/// stencil cache, so they're only compiled once in the process. Anything
+/// else, and modules that can't be read, is left to the wrapped loader.
+#[derive(Default)]
+pub(super) struct CachingLoader<L: ModuleLoader> {
+    inner: L,
+    /// Modules loaded by path, which have to be the same module every time
+    /// they're imported.
+    modules: HashMap<PathBuf, PermanentHeap<*mut JSObject>>,
+}
+
+impl<L: ModuleLoader> CachingLoader<L> {
+    fn resolve_path(cx: &Context, private: &Value, request: &ModuleRequest) -> Option<PathBuf> {
+        let specifier = request.specifier(cx).to_owned(cx).ok()?;
+        if specifier.starts_with('/') {
+            return Some(normalize(specifier.as_ref()));
+        }
+
+        if specifier.starts_with("./") || specifier.starts_with("../") {
+            let referrer = PathBuf::from(ModuleData::from_private(cx, private)?.path?);
+            return Some(normalize(&referrer.parent()?.join(specifier)));
+        }
+
+        None
+    }
+}
+
+impl<L: ModuleLoader> ModuleLoader for CachingLoader<L> {
+    fn resolve<'cx>(
+        &mut self,
+        cx: &'cx Context,
+        private: &Value,
+        request: &ModuleRequest,
+    ) -> ResultExc<Module<'cx>> {
+        let Some(path) = Self::resolve_path(cx, private, request) else {
+            return self.inner.resolve(cx, private, request);
+        };
+
+        let request = ModuleRequest::new(cx, path.to_string_lossy());
+        if let Some(module) = self.modules.get(&path) {
+            return self.inner.register(cx, module.get(), &request);
+        }
+
+        let Ok(code) = std::fs::read_to_string(&path) else {
+            return self.inner.resolve(cx, private, &request);
+        };
+
+        match sm_utils::compile_module(cx, &path, &code) {
+            Ok(module) => {
+                self.modules
+                    .insert(path, PermanentHeap::from_local(&module));
+                self.inner.register(cx, module.get(), &request)
+            }
+            Err(Some(report)) => Err(report.exception),
+            Err(None) => Err(Error::new(
+                &format!("Failed to compile module {}", path.to_string_lossy()),
+                ErrorKind::Normal,
+            )
+            .into()),
+        }
+    }
+
+    fn register<'cx>(
+        &mut self,
+        cx: &'cx Context,
+        module: *mut JSObject,
+        request: &ModuleRequest,
+    ) -> ResultExc<Module<'cx>> {
+        // The entry point is registered by path before it's evaluated
+        if let Ok(specifier) = request.specifier(cx).to_owned(cx) {
+            if specifier.starts_with('/') {
+                self.modules.insert(
+                    normalize(specifier.as_ref()),
+                    PermanentHeap::from_local(&cx.root(module)),
+                );
+            }
+        }
+
+        self.inner.register(cx, module, request)
+    }
+
+    fn metadata(&self, cx: &Context, private: &Value, meta: &Object) -> ResultExc<()> {
+        self.inner.metadata(cx, private, meta)
+    }
+}
@@ -1,23 +1,35 @@
-use anyhow::anyhow;
+use std::{collections::VecDeque, sync::Arc};
+
+use anyhow::{anyhow, bail};
 use futures::StreamExt;
 use ion::{Context, TracedHeap};
 use mozjs::{jsapi::JSContext, jsval::JSVal};
-use tokio::{select, sync::oneshot};
+use tokio::{
+    select,
+    sync::{oneshot, watch},
+};
 
 use crate::{
     builtins,
-    request_handlers::{Either, Request, RequestHandler, UserCode},
+    metrics::METRICS,
+    request_handlers::{service_workers, Either, Request, RequestHandler, UserCode},
     runners::ResponseData,
-    sm_utils::{error_report_option_to_anyhow_error, JsApp, TwoStandardModules},
+    sm_utils::{
+        self, error_report_option_to_anyhow_error, wait_for_out_of_memory, EnteredRealm,
+        HeapConfig, JsApp, TwoStandardModules,
+    },
 };
 
 use super::{
     event_loop_stream::EventLoopStream,
+    limits::{self, LimitExceeded, LimitedRequest, Watchdog},
+    module_loader::CachingLoader,
     request_queue::{RequestFinishedHandler, RequestFinishedResult, RequestQueue},
+    watch::RecordingLoader,
 };
 
 pub struct RequestData {
-    pub(super) _addr: std::net::SocketAddr,
+    pub(super) addr: std::net::SocketAddr,
     pub(super) req: http::request::Parts,
     pub(super) body: hyper::Body,
 }
@@ -38,19 +50,112 @@ impl std::fmt::Debug for ControlMessage {
     }
 }
 
+/// How far a worker got with evaluating the user code.
+#[derive(Clone, Debug)]
+pub enum WorkerState {
+    Starting,
+    /// The code was evaluated successfully, and requests are being accepted.
+    Ready,
+    /// Evaluating the code failed with the given error.
+    Failed(String),
+}
+
+/// Waits for a worker to evaluate the user code, returning the error if
+/// that failed.
+pub(super) async fn wait_until_initialized(
+    state: &mut watch::Receiver<WorkerState>,
+) -> anyhow::Result<()> {
+    let state = match state
+        .wait_for(|s| !matches!(s, WorkerState::Starting))
+        .await
+    {
+        Ok(state) => state.clone(),
+        Err(_) => bail!("The worker exited before evaluating the code"),
+    };
+
+    match state {
+        WorkerState::Ready => Ok(()),
+        WorkerState::Failed(e) => Err(anyhow!(e)),
+        WorkerState::Starting => unreachable!(),
+    }
+}
+
+/// How the JavaScript runtime of a worker is set up.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RuntimeConfig {
+    pub heap: HeapConfig,
+    /// Evaluate the user code again in a new realm for every request, so
+    /// nothing a request does is visible to the next one.
+    pub isolate_per_request: bool,
+}
+
+/// Why the request loop stopped.
+enum LoopExit {
+    Finished,
+    /// The runtime ran out of memory, and has to be built again.
+    OutOfMemory,
+}
+
+/// Thread-local state holding on to values from the runtime, which has to be
+/// released before the runtime is dropped so the next one on the thread
+/// starts out clean.
+struct ReleaseThreadState;
+
+impl Drop for ReleaseThreadState {
+    fn drop(&mut self) {
+        builtins::core::release_promise_hooks();
+        service_workers::event_listener::release_event_callback();
+    }
+}
+
 // Used to ignore errors when sending responses back, since
 // if the receiving end of the oneshot channel is dropped,
 // there really isn't anything we can do
 fn ignore_error<E>(_r: std::result::Result<(), E>) {}
 
+/// `state` is updated once the user code was evaluated, successfully or not.
+/// The limits of `watchdog` are enforced on every request. If the runtime
+/// runs out of memory, it's torn down and a fresh one takes over.
 pub(super) async fn handle_requests<H: RequestHandler + Copy + Unpin>(
     handler: H,
     user_code: UserCode,
     mut recv: tokio::sync::mpsc::UnboundedReceiver<ControlMessage>,
     max_request_threads: u32,
+    state: watch::Sender<WorkerState>,
+    watchdog: Arc<Watchdog>,
+    runtime: RuntimeConfig,
 ) {
-    if let Err(e) = handle_requests_inner(handler, user_code, &mut recv, max_request_threads).await
-    {
+    let _watchdog_thread = limits::start_watchdog(&watchdog);
+
+    let result = loop {
+        let result = limits::metered(handle_requests_inner(
+            handler,
+            &user_code,
+            &mut recv,
+            max_request_threads,
+            &state,
+            &watchdog,
+            &runtime,
+        ))
+        .await;
+
+        match result {
+            Ok(LoopExit::OutOfMemory) => {
+                METRICS.worker_out_of_memory();
+                tracing::error!("Worker thread ran out of memory, restarting its runtime");
+                // Requests wait in the channel until the code is evaluated again
+                state.send_replace(WorkerState::Starting);
+            }
+            Ok(LoopExit::Finished) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+
+    if let Err(e) = result {
+        if matches!(*state.borrow(), WorkerState::Starting) {
+            state.send_replace(WorkerState::Failed(format!("{e:?}")));
+        }
+
         // The request handling logic itself failed, so we send back the error
         // as long as the thread is alive and shutdown has not been requested.
         // This lets us report the error. The runner can shut us down as soon
@@ -71,16 +176,20 @@ pub(super) async fn handle_requests<H: RequestHandler + Copy + Unpin>(
 
 async fn handle_requests_inner<H: RequestHandler + Copy + Unpin>(
     mut handler: H,
-    user_code: UserCode,
+    user_code: &UserCode,
     recv: &mut tokio::sync::mpsc::UnboundedReceiver<ControlMessage>,
     max_request_threads: u32,
-) -> Result<(), anyhow::Error> {
+    state: &watch::Sender<WorkerState>,
+    watchdog: &Arc<Watchdog>,
+    runtime: &RuntimeConfig,
+) -> Result<LoopExit, anyhow::Error> {
     let is_module_mode = match user_code {
         UserCode::Script { .. } => false,
         UserCode::Directory(_) | UserCode::Module(_) => true,
     };
 
-    let module_loader = is_module_mode.then(runtime::module::Loader::default);
+    let module_loader =
+        is_module_mode.then(RecordingLoader::<CachingLoader<runtime::module::Loader>>::default);
     let standard_modules = TwoStandardModules(
         builtins::Modules {
             include_internal: is_module_mode,
@@ -89,12 +198,13 @@ async fn handle_requests_inner<H: RequestHandler + Copy + Unpin>(
         handler.get_standard_modules(),
     );
 
-    let js_app = JsApp::build(module_loader, Some(standard_modules));
+    let js_app = JsApp::build(module_loader, Some(standard_modules), &runtime.heap);
+    let _release = ReleaseThreadState;
     let cx = js_app.cx();
     let rt = js_app.rt();
     let mut event_loop_stream = EventLoopStream { app: &js_app };
 
-    handler.evaluate_scripts(cx, &user_code)?;
+    handler.evaluate_scripts(cx, user_code)?;
 
     // Wait for any promises resulting from running the script to be resolved, giving
     // scripts a chance to initialize before accepting requests
@@ -104,11 +214,39 @@ async fn handle_requests_inner<H: RequestHandler + Copy + Unpin>(
         .await
         .map_err(|e| error_report_option_to_anyhow_error(cx, e))?;
 
+    state.send_replace(WorkerState::Ready);
+
+    let _limits = limits::install(watchdog, cx);
+
     let mut request_queue = RequestQueue::new(cx);
 
     let mut shutdown_requested = false;
+    // In isolation mode, the realm of the request being handled, and the
+    // requests that arrived in the meantime, which wait until it's done
+    let mut request_realm = None;
+    let mut held_back = VecDeque::new();
 
     loop {
+        if request_realm.is_some() && request_queue.is_empty() {
+            request_realm = None;
+        }
+
+        if request_realm.is_none() {
+            if let Some((req, resp_tx)) = held_back.pop_front() {
+                request_realm = handle_isolated_request(
+                    &js_app,
+                    handler,
+                    user_code,
+                    max_request_threads,
+                    &mut request_queue,
+                    req,
+                    resp_tx,
+                )
+                .await;
+                continue;
+            }
+        }
+
         if shutdown_requested && rt.event_loop_is_empty() && request_queue.is_empty() {
             break;
         }
@@ -121,13 +259,18 @@ async fn handle_requests_inner<H: RequestHandler + Copy + Unpin>(
                     },
                     Some(ControlMessage::Terminate) => {
                         request_queue.cancel_all(RequestCancelledReason::ServerShuttingDown);
-                        return Ok(());
+                        for (_, resp_tx) in held_back {
+                            send_cancelled_response(resp_tx, RequestCancelledReason::ServerShuttingDown);
+                        }
+                        return Ok(LoopExit::Finished);
                     }
                     Some(ControlMessage::HandleRequest(req, resp_tx)) => {
                         if shutdown_requested {
                             ignore_error(resp_tx.send(ResponseData::ScriptError(Some(
                                 anyhow!("New request received after shutdown requested")
                             ))));
+                        } else if runtime.isolate_per_request {
+                            held_back.push_back((req, resp_tx));
                         } else {
                             handle_new_request(
                                 cx,
@@ -144,12 +287,32 @@ async fn handle_requests_inner<H: RequestHandler + Copy + Unpin>(
             // Nothing to do
             _ = request_queue.next() => (),
 
+            () = limits::wait_for_deadline() => limits::check_deadlines(),
+
+            () = wait_for_out_of_memory() => {
+                request_queue.cancel_all(RequestCancelledReason::OutOfMemory);
+                for (_, resp_tx) in held_back {
+                    send_cancelled_response(resp_tx, RequestCancelledReason::OutOfMemory);
+                }
+                if shutdown_requested {
+                    return Ok(LoopExit::Finished);
+                }
+                return Ok(LoopExit::OutOfMemory);
+            }
+
+            () = limits::wait_for_exceeded() => {
+                request_queue.cancel_where(|r| r.limited.exceeded().map(Into::into));
+            }
+
             // Nothing to do here except check the error
             e = event_loop_stream.next() => {
                 match e {
                     Some(Ok(())) => {
                         request_queue.cancel_unfinished(RequestCancelledReason::Unresolvable).await;
                     }
+                    // A script that ran over its limits was terminated, which
+                    // is reported through its request instead
+                    Some(Err(_)) if limits::take_terminated() => (),
                     Some(Err(e)) => {
                         // Note: an error in this stage is an unhandled error happening in the request
                         // logic, and such an error should not terminate the whole request processing
@@ -165,7 +328,97 @@ async fn handle_requests_inner<H: RequestHandler + Copy + Unpin>(
         }
     }
 
-    Ok(())
+    Ok(LoopExit::Finished)
+}
+
+/// Handles a request in a realm of its own, which is returned if the request
+/// is still in flight.
+async fn handle_isolated_request<H: RequestHandler + Copy + Unpin>(
+    js_app: &JsApp,
+    mut handler: H,
+    user_code: &UserCode,
+    max_request_threads: u32,
+    request_queue: &mut RequestQueue<RequestFinishedCallback<H>>,
+    req: RequestData,
+    resp_tx: oneshot::Sender<ResponseData>,
+) -> Option<RequestRealm> {
+    match RequestRealm::enter(js_app, &mut handler, user_code, max_request_threads).await {
+        Ok(realm) => {
+            handle_new_request(js_app.cx(), handler, request_queue, req, resp_tx);
+            Some(realm)
+        }
+        Err(e) => {
+            ignore_error(resp_tx.send(ResponseData::RequestError(e)));
+            None
+        }
+    }
+}
+
+/// The realm a request is handled in, in isolation mode. It stays entered
+/// until the request is done, and timers the request left behind are
+/// cleared when it's left, after which it can be collected.
+struct RequestRealm {
+    cx: *mut JSContext,
+    _realm: EnteredRealm,
+}
+
+impl RequestRealm {
+    async fn enter<H: RequestHandler + Copy + Unpin>(
+        js_app: &JsApp,
+        handler: &mut H,
+        user_code: &UserCode,
+        max_request_threads: u32,
+    ) -> anyhow::Result<Self> {
+        // Whatever is rooted while setting up the realm is released along
+        // with this context, rather than staying rooted as long as the runtime
+        let cx = &js_app.cx().duplicate();
+        let is_module_mode = match user_code {
+            UserCode::Script { .. } => false,
+            UserCode::Directory(_) | UserCode::Module(_) => true,
+        };
+
+        // The previous request's handler lives in its realm
+        builtins::core::release_promise_hooks();
+        service_workers::event_listener::release_event_callback();
+        unsafe { cx.get_private() }.app_data = None;
+
+        let module_loader =
+            is_module_mode.then(RecordingLoader::<CachingLoader<runtime::module::Loader>>::default);
+        let standard_modules = TwoStandardModules(
+            builtins::Modules {
+                include_internal: is_module_mode,
+                hardware_concurrency: max_request_threads,
+            },
+            handler.get_standard_modules(),
+        );
+        let realm = Self {
+            cx: cx.as_ptr(),
+            _realm: sm_utils::enter_new_realm(cx, module_loader, standard_modules)?,
+        };
+
+        sm_utils::evaluate_script(cx, include_str!("isolation.js"), "isolation.js")?;
+        handler.evaluate_scripts(cx, user_code)?;
+        js_app
+            .rt()
+            .run_event_loop()
+            .await
+            .map_err(|e| error_report_option_to_anyhow_error(cx, e))?;
+
+        Ok(realm)
+    }
+}
+
+impl Drop for RequestRealm {
+    fn drop(&mut self) {
+        let cx = unsafe { Context::new_unchecked(self.cx) };
+        if let Err(e) = sm_utils::evaluate_script(
+            &cx,
+            "globalThis.__winterjs_clear_timers?.()",
+            "isolation-cleanup.js",
+        ) {
+            tracing::debug!(error = %e, "Failed to clear the timers of a request");
+        }
+    }
 }
 
 fn handle_new_request<H: RequestHandler + Copy + Unpin>(
@@ -176,13 +429,25 @@ fn handle_new_request<H: RequestHandler + Copy + Unpin>(
     resp_tx: oneshot::Sender<ResponseData>,
 ) {
     tracing::trace!(%req.req.method, %req.req.uri, ?req.req.headers, "Incoming request");
-    match handler.start_handling_request(
-        cx.duplicate(),
-        Request {
-            parts: req.req,
-            body: req.body,
-        },
-    ) {
+    let limited = limits::track_request();
+    let result = limited.run(|| {
+        handler.start_handling_request(
+            cx.duplicate(),
+            Request {
+                parts: req.req,
+                body: req.body,
+                remote_addr: req.addr,
+            },
+        )
+    });
+
+    if let Some(exceeded) = limited.exceeded() {
+        limits::take_terminated();
+        send_cancelled_response(resp_tx, exceeded.into());
+        return;
+    }
+
+    match result {
         Err(f) => ignore_error(resp_tx.send(ResponseData::RequestError(f))),
         Ok(Either::Left(pending)) => request_queue.push(
             pending,
@@ -190,6 +455,7 @@ fn handle_new_request<H: RequestHandler + Copy + Unpin>(
                 cx: cx.as_ptr(),
                 handler,
                 resp_tx: Some(resp_tx),
+                limited,
             },
         ),
         Ok(Either::Right(resp)) => {
@@ -205,12 +471,37 @@ fn handle_new_request<H: RequestHandler + Copy + Unpin>(
 enum RequestCancelledReason {
     Unresolvable,
     ServerShuttingDown,
+    TimedOut,
+    CpuBudgetExceeded,
+    OutOfMemory,
+}
+
+impl RequestCancelledReason {
+    fn metrics_label(self) -> &'static str {
+        match self {
+            Self::Unresolvable => "unresolvable",
+            Self::ServerShuttingDown => "server_shutting_down",
+            Self::TimedOut => "timed_out",
+            Self::CpuBudgetExceeded => "cpu_budget_exceeded",
+            Self::OutOfMemory => "out_of_memory",
+        }
+    }
+}
+
+impl From<LimitExceeded> for RequestCancelledReason {
+    fn from(exceeded: LimitExceeded) -> Self {
+        match exceeded {
+            LimitExceeded::Timeout => Self::TimedOut,
+            LimitExceeded::CpuBudget => Self::CpuBudgetExceeded,
+        }
+    }
 }
 
 struct RequestFinishedCallback<H: RequestHandler + Copy + Unpin> {
     cx: *mut JSContext,
     handler: H,
     resp_tx: Option<oneshot::Sender<ResponseData>>,
+    limited: LimitedRequest,
 }
 
 impl<H: RequestHandler + Copy + Unpin> RequestFinishedCallback<H> {
@@ -228,9 +519,18 @@ impl<H: RequestHandler + Copy + Unpin> RequestFinishedHandler for RequestFinishe
         &mut self,
         result: Result<TracedHeap<JSVal>, TracedHeap<JSVal>>,
     ) -> RequestFinishedResult {
+        let handler = &mut self.handler;
+        let cx = self.cx;
         let response = self
-            .handler
-            .finish_request(unsafe { Context::new_unchecked(self.cx) }, result);
+            .limited
+            .run(|| handler.finish_request(unsafe { Context::new_unchecked(cx) }, result));
+
+        if let Some(exceeded) = self.limited.exceeded() {
+            limits::take_terminated();
+            self.request_cancelled(exceeded.into());
+            return RequestFinishedResult::Done;
+        }
+
         match response {
             Ok(Either::Left(pending)) => RequestFinishedResult::Pending(pending.promise),
             Ok(Either::Right(response)) => {
@@ -253,27 +553,59 @@ impl<H: RequestHandler + Copy + Unpin> RequestFinishedHandler for RequestFinishe
     }
 
     fn request_cancelled(&mut self, reason: RequestCancelledReason) {
-        match reason {
-            RequestCancelledReason::Unresolvable => {
-                let response = hyper::Response::builder()
-                    .status(500)
-                    .body(hyper::Body::from("The request could not be completed"))
-                    .expect("Failed to construct 500 response");
-                ignore_error(self.get_resp_tx().send(ResponseData::Done(response)));
-                tracing::warn!(
-                    "Request deemed impossible to complete since all IO-related promises \
-                have been resolved but the request's promise is still in pending state"
-                );
-            }
+        send_cancelled_response(self.get_resp_tx(), reason);
+    }
+}
 
-            RequestCancelledReason::ServerShuttingDown => {
-                let response = hyper::Response::builder()
-                    .status(503)
-                    .body(hyper::Body::from("Server is shutting down"))
-                    .expect("Failed to construct 503 response");
+fn send_cancelled_response(resp_tx: oneshot::Sender<ResponseData>, reason: RequestCancelledReason) {
+    METRICS.request_cancelled(reason.metrics_label());
 
-                ignore_error(self.get_resp_tx().send(ResponseData::Done(response)));
-            }
+    match reason {
+        RequestCancelledReason::Unresolvable => {
+            let response = hyper::Response::builder()
+                .status(500)
+                .body(hyper::Body::from("The request could not be completed"))
+                .expect("Failed to construct 500 response");
+            ignore_error(resp_tx.send(ResponseData::Done(response)));
+            tracing::warn!(
+                "Request deemed impossible to complete since all IO-related promises \
+            have been resolved but the request's promise is still in pending state"
+            );
+        }
+
+        RequestCancelledReason::ServerShuttingDown => {
+            let response = hyper::Response::builder()
+                .status(503)
+                .body(hyper::Body::from("Server is shutting down"))
+                .expect("Failed to construct 503 response");
+
+            ignore_error(resp_tx.send(ResponseData::Done(response)));
+        }
+
+        RequestCancelledReason::TimedOut => {
+            let response = hyper::Response::builder()
+                .status(504)
+                .body(hyper::Body::from("The request timed out"))
+                .expect("Failed to construct 504 response");
+            ignore_error(resp_tx.send(ResponseData::Done(response)));
+            tracing::warn!("Request timed out before the script responded");
+        }
+
+        RequestCancelledReason::CpuBudgetExceeded => {
+            let response = hyper::Response::builder()
+                .status(500)
+                .body(hyper::Body::from("The request used too much CPU time"))
+                .expect("Failed to construct 500 response");
+            ignore_error(resp_tx.send(ResponseData::Done(response)));
+            tracing::warn!("Request exceeded its CPU time budget before the script responded");
+        }
+
+        RequestCancelledReason::OutOfMemory => {
+            let response = hyper::Response::builder()
+                .status(500)
+                .body(hyper::Body::from("The server ran out of memory"))
+                .expect("Failed to construct 500 response");
+            ignore_error(resp_tx.send(ResponseData::Done(response)));
         }
     }
 }
@@ -59,6 +59,18 @@ impl<F: RequestFinishedHandler> RequestQueue<F> {
         }
     }
 
+    /// Cancels the requests `reason` returns a reason for, keeping the others.
+    pub fn cancel_where(&mut self, mut reason: impl FnMut(&F) -> Option<F::CancelReason>) {
+        let mut requests = FuturesUnordered::new();
+        std::mem::swap(&mut requests, &mut self.requests);
+        for mut req in requests.into_iter() {
+            match reason(&req.on_finished) {
+                Some(cancel_reason) => req.on_finished.request_cancelled(cancel_reason),
+                None => self.requests.push(req),
+            }
+        }
+    }
+
     pub fn cancel_unfinished(&mut self, cancel_reason: F::CancelReason) -> CancelUnfinished<'_, F> {
         CancelUnfinished {
             queue: self,
@@ -4,138 +4,654 @@
 //! right now. Maybe I'll rename it later.
 
 use std::{
-    sync::{atomic::AtomicI32, Arc},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
-use anyhow::anyhow;
+use anyhow::{anyhow, bail, Context};
+use arc_swap::ArcSwap;
 use async_trait::async_trait;
-use tokio::{sync::Mutex, task::LocalSet};
+use rand::Rng;
+use tokio::{
+    sync::{watch, Mutex},
+    task::LocalSet,
+};
 
 use crate::{
-    request_handlers::{RequestHandler, UserCode},
+    metrics::{WorkerCounters, METRICS},
+    request_handlers::{RequestHandler, UserCode, UserCodeSource},
     runners::{request_loop::handle_requests, ResponseData},
 };
 
-use super::request_loop::{ControlMessage, RequestData};
+use super::{
+    dispatcher::{AffinityKey, Dispatch, Dispatcher, SchedulingPolicy, WorkerHandle},
+    limits::{RequestLimits, Watchdog},
+    request_loop::{
+        wait_until_initialized, ControlMessage, RequestData, RuntimeConfig, WorkerState,
+    },
+};
+
+/// How often worker threads are checked for failures.
+const SUPERVISION_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long to wait before replacing a failed worker thread. This doubles
+/// with every consecutive failure, up to `MAX_RESPAWN_DELAY`.
+const INITIAL_RESPAWN_DELAY: Duration = Duration::from_millis(250);
+const MAX_RESPAWN_DELAY: Duration = Duration::from_secs(10);
+
+/// After this many worker threads failed in a row without any of them
+/// becoming ready, we stop replacing them until the code is reloaded.
+const MAX_CONSECUTIVE_FAILURES: u32 = 5;
+
+/// How often the number of shed requests is logged, if any were shed.
+const SHED_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The Retry-After header sent along with shed requests, in seconds.
+const SHED_RETRY_AFTER: &str = "1";
+
+/// How many worker threads to run, and when to stop the ones that aren't
+/// needed anymore.
+#[derive(Clone, Debug)]
+pub struct WorkerPoolConfig {
+    /// Threads that are started and initialized before taking requests, and
+    /// kept around when idle.
+    pub min_threads: usize,
+    pub max_threads: usize,
+    /// Threads beyond `min_threads` are stopped when they haven't handled a
+    /// request for this long. If `None`, they're kept forever.
+    pub idle_timeout: Option<Duration>,
+    pub policy: SchedulingPolicy,
+    /// Requests with this key are always sent to the same thread, as long
+    /// as no threads are started or stopped.
+    pub affinity: Option<AffinityKey>,
+    /// Requests a thread handles at once. Beyond that, requests wait in a
+    /// queue for a thread to have room.
+    pub max_in_flight_per_thread: Option<u32>,
+    /// Requests that can wait in the queue, beyond which they're shed.
+    pub max_queued_requests: Option<usize>,
+    /// Threads running JavaScript they can't be interrupted in when a
+    /// request goes over these limits are replaced.
+    pub request_limits: RequestLimits,
+    /// Applied to the JavaScript runtime of every thread. A thread that runs
+    /// out of memory fails its requests and starts a fresh runtime.
+    pub runtime: RuntimeConfig,
+    /// Threads are replaced after handling this many requests, or after
+    /// running for this long, to get rid of anything the code leaked.
+    pub max_requests_per_thread: Option<u64>,
+    pub max_thread_age: Option<Duration>,
+}
 
 pub struct WorkerThreadInfo {
+    handle: Arc<WorkerHandle>,
     thread: std::thread::JoinHandle<()>,
-    channel: tokio::sync::mpsc::UnboundedSender<ControlMessage>,
-    in_flight_requests: Arc<AtomicI32>,
+    /// When the thread was last seen handling a request.
+    last_active: Instant,
+    /// The number of requests dispatched to the thread when it was last
+    /// checked for activity.
+    last_dispatched_requests: u64,
+    /// Set once the failure of this thread was noticed and dealt with.
+    failure_handled: bool,
+    /// When the thread is to be replaced because of its age.
+    recycle_at: Option<Instant>,
 }
 
 impl WorkerThreadInfo {
+    fn spawn<H: RequestHandler + Copy + Unpin>(
+        index: usize,
+        handler: H,
+        user_code: UserCode,
+        config: &WorkerPoolConfig,
+    ) -> Self {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let (state_tx, state_rx) = watch::channel(WorkerState::Starting);
+        let watchdog = Arc::new(Watchdog::new(config.request_limits));
+        let watchdog_clone = watchdog.clone();
+        let max_threads = config.max_threads;
+        let runtime = config.runtime;
+        let join_handle = std::thread::spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(async move {
+                    let local_set = LocalSet::new();
+                    local_set
+                        .run_until(handle_requests(
+                            handler,
+                            user_code,
+                            rx,
+                            max_threads as u32,
+                            state_tx,
+                            watchdog_clone,
+                            runtime,
+                        ))
+                        .await
+                })
+        });
+        let handle = WorkerHandle {
+            index,
+            channel: tx,
+            counters: Arc::new(WorkerCounters::default()),
+            state: state_rx,
+            watchdog,
+        };
+        // Ages are spread out by up to a tenth, so threads started together
+        // aren't all replaced at once
+        let recycle_at = config
+            .max_thread_age
+            .map(|age| Instant::now() + age.mul_f64(rand::thread_rng().gen_range(0.9..=1.0)));
+        Self {
+            handle: Arc::new(handle),
+            thread: join_handle,
+            last_active: Instant::now(),
+            last_dispatched_requests: 0,
+            failure_handled: false,
+            recycle_at,
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.handle.index
+    }
+
     pub fn is_finished(&self) -> bool {
         self.thread.is_finished()
     }
+
+    pub fn is_ready(&self) -> bool {
+        !self.is_finished() && matches!(*self.handle.state.borrow(), WorkerState::Ready)
+    }
+
+    /// Whether the thread died, or is only answering requests with the
+    /// error it got while evaluating the code.
+    pub fn has_failed(&self) -> bool {
+        self.is_finished() || matches!(*self.handle.state.borrow(), WorkerState::Failed(_))
+    }
+
+    /// Whether the thread handled too many requests or ran for too long.
+    fn is_worn_out(&self, config: &WorkerPoolConfig) -> bool {
+        let requests = self
+            .handle
+            .counters
+            .dispatched_requests
+            .load(Ordering::SeqCst);
+        config
+            .max_requests_per_thread
+            .is_some_and(|max| requests >= max)
+            || self.recycle_at.is_some_and(|at| Instant::now() >= at)
+    }
+
+    /// Whether the thread is stuck in JavaScript it can't be interrupted in.
+    pub fn is_unresponsive(&self) -> bool {
+        self.handle.watchdog.is_unresponsive()
+    }
+
+    fn send(&self, message: ControlMessage) {
+        _ = self.handle.channel.send(message);
+    }
 }
 
-// TODO: replace failing threads
 pub struct SingleRunner<H: RequestHandler + Copy + Unpin> {
     threads: Vec<WorkerThreadInfo>,
-    max_threads: usize,
+    /// Threads running a previous version of the code, which are finishing
+    /// the requests they already received.
+    retired_threads: Vec<WorkerThreadInfo>,
+    /// Threads that take the place of the thread with the same index once
+    /// they're ready, because that one is worn out.
+    replacements: Vec<WorkerThreadInfo>,
+    config: WorkerPoolConfig,
     handler: H,
     user_code: UserCode,
+    user_code_source: UserCodeSource,
+    reloading: bool,
+    dispatcher: Arc<Dispatcher>,
+    status: Arc<PoolStatus>,
+    /// Worker threads that failed since one last became ready.
+    consecutive_failures: u32,
+    /// No new threads are spawned before this time after a failure.
+    respawn_after: Option<Instant>,
+    /// Set when the code keeps failing to initialize, and we stopped trying.
+    gave_up: bool,
+    /// The number of shed requests when that was last logged.
+    shed_reported: u64,
+    shed_reported_at: Instant,
     shut_down: bool,
 }
 
-pub type SharedSingleRunner<H> = Arc<Mutex<SingleRunner<H>>>;
+/// The threads health probes look at, published along with the threads
+/// requests are dispatched to, so probes don't wait for the runner lock.
+#[derive(Default)]
+struct PoolStatus {
+    threads: ArcSwap<Vec<Arc<WorkerHandle>>>,
+    shut_down: AtomicBool,
+}
+
+impl PoolStatus {
+    fn get(&self) -> crate::server::RunnerStatus {
+        // A thread's receiving end of the channel is dropped once it exits
+        let threads = self.threads.load();
+        let state = |t: &WorkerHandle| (t.channel.is_closed(), t.state.borrow().clone());
+        crate::server::RunnerStatus {
+            ready: !self.shut_down.load(Ordering::SeqCst)
+                && threads
+                    .iter()
+                    .any(|t| matches!(state(t), (false, WorkerState::Ready))),
+            alive: threads.iter().any(|t| {
+                matches!(
+                    state(t),
+                    (false, WorkerState::Starting | WorkerState::Ready)
+                )
+            }),
+        }
+    }
+}
+
+/// Requests are dispatched without locking the runner, which is only needed
+/// to spawn new threads.
+pub struct SharedSingleRunner<H: RequestHandler + Copy + Unpin> {
+    runner: Arc<Mutex<SingleRunner<H>>>,
+    dispatcher: Arc<Dispatcher>,
+    status: Arc<PoolStatus>,
+}
+
+impl<H: RequestHandler + Copy + Unpin> Clone for SharedSingleRunner<H> {
+    fn clone(&self) -> Self {
+        Self {
+            runner: self.runner.clone(),
+            dispatcher: self.dispatcher.clone(),
+            status: self.status.clone(),
+        }
+    }
+}
 
 impl<H: RequestHandler + Copy + Unpin> SingleRunner<H> {
-    pub fn new(max_threads: usize, handler: H, user_code: UserCode) -> Self {
-        if max_threads == 0 {
-            panic!("max_threads must be at least 1");
+    pub fn new(
+        config: WorkerPoolConfig,
+        handler: H,
+        user_code: UserCode,
+        user_code_source: UserCodeSource,
+    ) -> Self {
+        if config.min_threads == 0 {
+            panic!("min_threads must be at least 1");
+        }
+        if config.min_threads > config.max_threads {
+            panic!("min_threads must not be more than max_threads");
         }
 
+        let dispatcher = Dispatcher::new(&config);
+
         Self {
             threads: vec![],
-            max_threads,
+            retired_threads: vec![],
+            replacements: vec![],
+            config,
             handler,
             user_code,
+            user_code_source,
+            reloading: false,
+            dispatcher: Arc::new(dispatcher),
+            status: Arc::new(PoolStatus::default()),
+            consecutive_failures: 0,
+            respawn_after: None,
+            gave_up: false,
+            shed_reported: METRICS.shed_requests(),
+            shed_reported_at: Instant::now(),
             shut_down: false,
         }
     }
 
+    /// `user_code_source` is where `user_code` was loaded from. It's loaded
+    /// from there again when reloading.
+    ///
+    /// The minimum number of threads is started before returning, and the
+    /// code is evaluated on all of them, so errors in it are reported at
+    /// startup and no request has to wait for a thread to initialize.
     pub fn new_request_handler(
         handler: H,
-        max_threads: usize,
+        config: WorkerPoolConfig,
         user_code: UserCode,
-    ) -> SharedSingleRunner<H> {
-        Arc::new(Mutex::new(Self::new(max_threads, handler, user_code)))
+        user_code_source: UserCodeSource,
+    ) -> anyhow::Result<SharedSingleRunner<H>> {
+        let min_threads = config.min_threads;
+        let mut runner = Self::new(config, handler, user_code, user_code_source);
+        for _ in 0..min_threads {
+            runner.spawn_thread();
+        }
+        futures::executor::block_on(wait_until_all_initialized(&runner.threads))
+            .context("Failed to evaluate the code")?;
+        tracing::debug!("Started {min_threads} handler threads");
+
+        let dispatcher = runner.dispatcher.clone();
+        let status = runner.status.clone();
+
+        Ok(SharedSingleRunner {
+            runner: Arc::new(Mutex::new(runner)),
+            dispatcher,
+            status,
+        })
     }
 
-    fn spawn_thread(&mut self) -> &WorkerThreadInfo {
-        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
-        let handler = self.handler;
-        let user_code = self.user_code.clone();
-        let max_threads = self.max_threads;
-        let join_handle = std::thread::spawn(move || {
-            tokio::runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .unwrap()
-                .block_on(async move {
-                    let local_set = LocalSet::new();
-                    local_set
-                        .run_until(handle_requests(handler, user_code, rx, max_threads as u32))
-                        .await
-                })
-        });
-        let worker = WorkerThreadInfo {
-            thread: join_handle,
-            channel: tx,
-            in_flight_requests: Arc::new(AtomicI32::new(0)),
-        };
+    fn spawn_thread(&mut self) -> Arc<WorkerHandle> {
+        let worker = WorkerThreadInfo::spawn(
+            self.threads.len(),
+            self.handler,
+            self.user_code.clone(),
+            &self.config,
+        );
+        tracing::debug!("Starting new handler thread #{}", worker.index());
+        METRICS.set_worker(worker.index(), worker.handle.counters.clone());
+        let handle = worker.handle.clone();
         self.threads.push(worker);
-        let spawned_index = self.threads.len() - 1;
-        tracing::debug!("Starting new handler thread #{spawned_index}");
-        &self.threads[spawned_index]
+        self.publish_threads();
+        handle
+    }
+
+    /// Lets the dispatcher know which threads requests may be sent to, and
+    /// health probes which threads there are.
+    fn publish_threads(&self) {
+        self.status.threads.store(Arc::new(
+            self.threads.iter().map(|t| t.handle.clone()).collect(),
+        ));
+        self.status
+            .shut_down
+            .store(self.shut_down, Ordering::SeqCst);
+
+        let workers = if self.shut_down {
+            vec![]
+        } else {
+            self.threads
+                .iter()
+                .filter(|t| !t.failure_handled)
+                .map(|t| t.handle.clone())
+                .collect()
+        };
+        self.dispatcher.publish(workers);
     }
 
-    fn find_or_spawn_thread(&mut self) -> Option<&WorkerThreadInfo> {
-        if self.shut_down {
-            return None;
+    fn may_spawn_threads(&self) -> bool {
+        match self.respawn_after {
+            _ if self.gave_up => false,
+            Some(after) => Instant::now() >= after,
+            None => true,
         }
+    }
 
-        let request_counts = self
-            .threads
-            .iter()
-            .enumerate()
-            .map(|(idx, t)| {
-                (
-                    idx,
-                    t.in_flight_requests
-                        .load(std::sync::atomic::Ordering::SeqCst),
-                )
-            })
-            .collect::<Vec<_>>();
+    /// Delays spawning threads after one failed, giving up after too many
+    /// failures in a row.
+    fn back_off(&mut self) {
+        self.consecutive_failures += 1;
+        let delay = INITIAL_RESPAWN_DELAY
+            .saturating_mul(1 << (self.consecutive_failures - 1).min(16))
+            .min(MAX_RESPAWN_DELAY);
+        self.respawn_after = Some(Instant::now() + delay);
+
+        if self.consecutive_failures >= MAX_CONSECUTIVE_FAILURES && !self.gave_up {
+            self.gave_up = true;
+            tracing::error!(
+                "Worker threads failed {} times in a row, giving up on replacing them; \
+                fix the code and reload it to try again",
+                self.consecutive_failures
+            );
+        }
+    }
+
+    /// Notices worker threads that died or failed to evaluate the code, and
+    /// replaces them once the backoff delay has passed.
+    fn replace_failed_threads(&mut self) {
+        if self.threads.iter().any(|t| t.is_ready()) {
+            self.consecutive_failures = 0;
+        }
 
-        // Step 1: are there any idle threads?
-        for t in &request_counts {
-            if t.1 <= 0 {
-                tracing::debug!("Using idle handler thread #{}", t.0);
-                return Some(&self.threads[t.0]);
+        let mut changed = false;
+        for index in 0..self.threads.len() {
+            let thread = &mut self.threads[index];
+            if thread.failure_handled || !thread.has_failed() {
+                continue;
             }
+            thread.failure_handled = true;
+            changed = true;
+
+            match &*thread.handle.state.borrow() {
+                WorkerState::Failed(e) => {
+                    tracing::error!("Worker thread #{} failed to initialize", thread.index());
+                    println!("{e}");
+                }
+                _ => tracing::error!("Worker thread #{} died unexpectedly", thread.index()),
+            }
+            // Let it go, it's no use to anyone anymore
+            thread.send(ControlMessage::Shutdown);
+
+            self.back_off();
         }
 
-        // Step 2: can we spawn a new thread?
-        if self.threads.len() < self.max_threads {
-            tracing::debug!("Spawning new request handler thread");
-            return Some(self.spawn_thread());
+        // Replace one thread at a time, so we find out whether the code
+        // works before trying again with the others
+        let failed = self.threads.iter().position(|t| t.failure_handled);
+        if let (Some(index), true) = (failed, self.may_spawn_threads()) {
+            tracing::info!("Replacing worker thread #{index}");
+            let worker =
+                WorkerThreadInfo::spawn(index, self.handler, self.user_code.clone(), &self.config);
+            METRICS.set_worker(index, worker.handle.counters.clone());
+            self.threads[index] = worker;
+            changed = true;
         }
 
-        // Step 3: find the thread with the least active requests
-        // unwrap safety: request_counts can never be empty
-        let min = request_counts.iter().min_by_key(|t| t.1).unwrap();
-        tracing::debug!(
-            "Reusing busy handler thread #{} with in-flight request count {}",
-            min.0,
-            self.threads[min.0]
-                .in_flight_requests
-                .load(std::sync::atomic::Ordering::SeqCst)
-        );
-        Some(&self.threads[min.0])
+        if changed {
+            self.publish_threads();
+        }
+    }
+
+    /// Replaces threads stuck in JavaScript that couldn't be interrupted
+    /// when a request went over its limits. There's no way to stop them, so
+    /// they're left to themselves, and quit if they ever get unstuck.
+    fn replace_unresponsive_threads(&mut self) {
+        let mut changed = false;
+        for index in 0..self.threads.len() {
+            if !self.threads[index].is_unresponsive() {
+                continue;
+            }
+
+            tracing::error!("Worker thread #{index} could not be interrupted, replacing it");
+            let worker =
+                WorkerThreadInfo::spawn(index, self.handler, self.user_code.clone(), &self.config);
+            METRICS.set_worker(index, worker.handle.counters.clone());
+            let stuck = std::mem::replace(&mut self.threads[index], worker);
+            stuck.send(ControlMessage::Terminate);
+            changed = true;
+        }
+        self.retired_threads.retain(|t| !t.is_unresponsive());
+
+        if changed {
+            self.publish_threads();
+        }
+    }
+
+    /// Starts replacements for threads that handled too many requests or ran
+    /// for too long, and swaps them in once they're ready. The old threads
+    /// then finish the requests they already have and quit, so no request
+    /// has to wait for a new thread to evaluate the code.
+    fn recycle_worn_out_threads(&mut self) {
+        let mut changed = false;
+        for replacement in std::mem::take(&mut self.replacements) {
+            let index = replacement.index();
+            if replacement.has_failed() {
+                tracing::error!("Replacement for worker thread #{index} failed to initialize");
+                if let WorkerState::Failed(e) = &*replacement.handle.state.borrow() {
+                    println!("{e}");
+                }
+                replacement.send(ControlMessage::Shutdown);
+                self.retired_threads.push(replacement);
+                self.back_off();
+            } else if !replacement.is_ready() {
+                self.replacements.push(replacement);
+            } else {
+                tracing::debug!("Replaced worn out handler thread #{index}");
+                METRICS.set_worker(index, replacement.handle.counters.clone());
+                let old = std::mem::replace(&mut self.threads[index], replacement);
+                old.send(ControlMessage::Shutdown);
+                self.retired_threads.push(old);
+                changed = true;
+            }
+        }
+
+        if changed {
+            self.publish_threads();
+        }
+
+        for index in 0..self.threads.len() {
+            let thread = &self.threads[index];
+            if thread.failure_handled
+                || !thread.is_worn_out(&self.config)
+                || self.replacements.iter().any(|r| r.index() == index)
+                || !self.may_spawn_threads()
+            {
+                continue;
+            }
+
+            tracing::debug!("Starting replacement for worn out handler thread #{index}");
+            let replacement =
+                WorkerThreadInfo::spawn(index, self.handler, self.user_code.clone(), &self.config);
+            self.replacements.push(replacement);
+        }
+    }
+
+    /// Stops surplus threads that haven't handled a request for a while,
+    /// down to the minimum. With the least-in-flight policies, requests go
+    /// to the first idle thread, so the last ones are the first to go quiet
+    /// and are stopped first.
+    fn reap_idle_threads(&mut self) {
+        let now = Instant::now();
+        for thread in &mut self.threads {
+            let counters = &thread.handle.counters;
+            let dispatched = counters.dispatched_requests.load(Ordering::SeqCst);
+            if dispatched != thread.last_dispatched_requests
+                || counters.in_flight_requests.load(Ordering::SeqCst) > 0
+            {
+                thread.last_active = now;
+                thread.last_dispatched_requests = dispatched;
+            }
+        }
+        self.retired_threads.retain(|t| !t.is_finished());
+
+        let Some(idle_timeout) = self.config.idle_timeout else {
+            return;
+        };
+
+        let mut changed = false;
+        while self.threads.len() > self.config.min_threads {
+            let last = &self.threads[self.threads.len() - 1];
+            if now.duration_since(last.last_active) < idle_timeout {
+                break;
+            }
+
+            let thread = self.threads.pop().unwrap();
+            tracing::debug!("Stopping idle handler thread #{}", thread.index());
+            thread.send(ControlMessage::Shutdown);
+            self.retired_threads.push(thread);
+            changed = true;
+        }
+
+        if changed {
+            self.retire_replacements(self.threads.len());
+            self.publish_threads();
+            METRICS.truncate_workers(self.threads.len());
+        }
+    }
+
+    /// Stops the replacements meant for threads from index `from` on.
+    fn retire_replacements(&mut self, from: usize) {
+        let (retired, kept) = std::mem::take(&mut self.replacements)
+            .into_iter()
+            .partition(|r| r.index() >= from);
+        self.replacements = kept;
+        for replacement in &retired {
+            replacement.send(ControlMessage::Shutdown);
+        }
+        self.retired_threads.extend(retired);
+    }
+
+    /// Logs how many requests were shed since this was last logged.
+    fn report_shed_requests(&mut self) {
+        if self.shed_reported_at.elapsed() < SHED_REPORT_INTERVAL {
+            return;
+        }
+
+        let shed = METRICS.shed_requests();
+        if shed > self.shed_reported {
+            tracing::warn!(
+                "Shed {} requests in the last {} seconds, because all worker threads \
+                and the request queue were full",
+                shed - self.shed_reported,
+                self.shed_reported_at.elapsed().as_secs()
+            );
+        }
+        self.shed_reported = shed;
+        self.shed_reported_at = Instant::now();
+    }
+}
+
+/// Waits for all threads to evaluate the code. If that fails on any of
+/// them, they're all shut down.
+async fn wait_until_all_initialized(threads: &[WorkerThreadInfo]) -> anyhow::Result<()> {
+    let result = futures::future::try_join_all(threads.iter().map(|t| {
+        let mut state = t.handle.state.clone();
+        async move { wait_until_initialized(&mut state).await }
+    }))
+    .await;
+
+    if result.is_err() {
+        for thread in threads {
+            thread.send(ControlMessage::Shutdown);
+        }
+    }
+    result.map(|_| ())
+}
+
+/// Loads the code again and starts the minimum number of worker threads
+/// running it. The threads are only returned once they have all evaluated
+/// the code successfully.
+async fn start_reloaded_workers<H: RequestHandler + Copy + Unpin>(
+    handler: H,
+    config: &WorkerPoolConfig,
+    source: &UserCodeSource,
+) -> anyhow::Result<(Vec<WorkerThreadInfo>, UserCode)> {
+    let user_code = source.load()?;
+    let workers = (0..config.min_threads)
+        .map(|index| WorkerThreadInfo::spawn(index, handler, user_code.clone(), config))
+        .collect::<Vec<_>>();
+
+    wait_until_all_initialized(&workers)
+        .await
+        .context("The new code failed to initialize")?;
+
+    Ok((workers, user_code))
+}
+
+impl<H: RequestHandler + Copy + Unpin> SharedSingleRunner<H> {
+    /// The slow path of dispatching a request, taken when the dispatcher
+    /// wants a new thread or found none to use. Returns the reason if no
+    /// thread can take the request.
+    async fn spawn_or_choose_thread(&self) -> Result<Dispatch, &'static str> {
+        let mut this = self.runner.lock().await;
+        if this.shut_down {
+            return Err("Server is shutting down");
+        }
+
+        // Another request may have spawned a thread while we were waiting
+        // for the lock, so check again
+        let may_spawn = this.threads.len() < this.config.max_threads && this.may_spawn_threads();
+        match self.dispatcher.choose(may_spawn) {
+            Dispatch::Spawn => {
+                tracing::debug!("Spawning new request handler thread");
+                Ok(Dispatch::Worker(this.spawn_thread()))
+            }
+            dispatch @ (Dispatch::Worker(_) | Dispatch::Full) => Ok(dispatch),
+            Dispatch::Unavailable if this.gave_up => Err("The application failed to start"),
+            Dispatch::Unavailable => Err("No worker threads are available, try again later"),
+        }
     }
 }
 
@@ -143,41 +659,86 @@ impl<H: RequestHandler + Copy + Unpin> SingleRunner<H> {
 impl<H: RequestHandler + Copy + Unpin> crate::server::Runner for SharedSingleRunner<H> {
     async fn handle(
         &self,
-        _addr: std::net::SocketAddr,
+        addr: std::net::SocketAddr,
         req: http::request::Parts,
         body: hyper::Body,
     ) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
-        let mut this = self.lock().await;
-        let Some(thread) = this.find_or_spawn_thread() else {
-            let response = hyper::Response::builder()
-                .status(503)
-                .body(hyper::Body::from("Server is shutting down"))
-                .expect("Failed to construct 503 response");
-            return Ok(response);
-        };
+        let mut queued = None;
+        let (worker, in_flight) = loop {
+            let room_freed = self.dispatcher.room_freed();
+            tokio::pin!(room_freed);
+            room_freed.as_mut().enable();
+
+            let dispatch = match self.dispatcher.choose_by_affinity(addr, &req) {
+                Some(dispatch) => dispatch,
+                None => match self.dispatcher.choose(true) {
+                    Dispatch::Spawn | Dispatch::Unavailable => {
+                        match self.spawn_or_choose_thread().await {
+                            Ok(dispatch) => dispatch,
+                            Err(message) => return Ok(service_unavailable(message, false)),
+                        }
+                    }
+                    dispatch => dispatch,
+                },
+            };
+
+            if let Dispatch::Worker(worker) = dispatch {
+                match self.dispatcher.acquire(&worker) {
+                    Some(in_flight) => break (worker, in_flight),
+                    // Another request took the last spot on the thread
+                    None => continue,
+                }
+            }
 
-        let request_count = thread.in_flight_requests.clone();
-        let increment_guard = IncrementGuard::new(request_count);
+            // All threads are at the in-flight request limit, so wait for one
+            // of them to have room
+            if queued.is_none() {
+                queued = self.dispatcher.enqueue();
+                if queued.is_none() {
+                    METRICS.request_shed();
+                    return Ok(service_unavailable(
+                        "The server is overloaded, try again later",
+                        true,
+                    ));
+                }
+            }
+            room_freed.await;
+        };
+        drop(queued);
 
+        let worker_index = worker.index;
         let (tx, rx) = tokio::sync::oneshot::channel();
 
-        thread.channel.send(ControlMessage::HandleRequest(
-            RequestData { _addr, req, body },
+        worker.channel.send(ControlMessage::HandleRequest(
+            RequestData { addr, req, body },
             tx,
         ))?;
+        let watchdog = worker.watchdog.clone();
+        drop(worker);
+
+        let response = tokio::select! {
+            response = rx => response?,
+            // The thread will be replaced, but it's not going to answer
+            () = watchdog.wait_until_unresponsive() => {
+                METRICS.request_cancelled("worker_unresponsive");
+                return Ok(hyper::Response::builder()
+                    .status(504)
+                    .body(hyper::Body::from("The request timed out"))
+                    .expect("Failed to construct 504 response"));
+            }
+        };
 
-        // explicitly drop mutex guard to unlock mutex
-        drop(this);
-
-        let response = rx.await?;
-
-        drop(increment_guard);
+        drop(in_flight);
 
-        // TODO: handle script errors
         match response {
-            ResponseData::Done(resp) => Ok(resp),
+            ResponseData::Done(mut resp) => {
+                resp.extensions_mut()
+                    .insert(crate::server::WorkerIndex(worker_index));
+                Ok(resp)
+            }
             ResponseData::RequestError(err) => Err(err),
             ResponseData::ScriptError(err) => {
+                METRICS.script_error();
                 if let Some(err) = err {
                     println!("{err:?}");
                 }
@@ -186,14 +747,95 @@ impl<H: RequestHandler + Copy + Unpin> crate::server::Runner for SharedSingleRun
         }
     }
 
-    async fn shutdown(&self, timeout: Option<Duration>) {
+    async fn status(&self) -> crate::server::RunnerStatus {
+        self.status.get()
+    }
+
+    async fn supervise(&self) {
+        let mut interval = tokio::time::interval(SUPERVISION_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+
+            let mut this = self.runner.lock().await;
+            if this.shut_down {
+                break;
+            }
+            this.replace_failed_threads();
+            this.replace_unresponsive_threads();
+            this.recycle_worn_out_threads();
+            this.reap_idle_threads();
+            this.report_shed_requests();
+        }
+    }
+
+    async fn reload(&self) -> anyhow::Result<()> {
+        let mut this = self.runner.lock().await;
+        if this.shut_down {
+            bail!("Server is shutting down");
+        }
+        if this.reloading {
+            bail!("A reload is already in progress");
+        }
+        this.reloading = true;
+        let (handler, config) = (this.handler, this.config.clone());
+        let source = this.user_code_source.clone();
+        // Keep serving requests on the current threads while the new code
+        // is initializing
+        drop(this);
+
+        tracing::info!(path = %source.path.display(), "Reloading code");
+        let result = start_reloaded_workers(handler, &config, &source).await;
+
+        let mut this = self.runner.lock().await;
+        this.reloading = false;
+        let (workers, user_code) = result?;
+
+        if this.shut_down {
+            for worker in &workers {
+                worker.send(ControlMessage::Shutdown);
+            }
+            this.retired_threads.extend(workers);
+            bail!("Server is shutting down");
+        }
+
+        // The old threads finish the requests they already have and quit,
+        // while new requests go to the new code.
+        this.retired_threads.retain(|t| !t.is_finished());
+        let old_threads = std::mem::replace(&mut this.threads, workers);
+        for thread in &old_threads {
+            if !thread.is_finished() {
+                thread.send(ControlMessage::Shutdown);
+            }
+        }
+        this.retired_threads.extend(old_threads);
+        // Replacements run the old code too
+        this.retire_replacements(0);
+        this.user_code = user_code;
+        this.consecutive_failures = 0;
+        this.respawn_after = None;
+        this.gave_up = false;
+
+        this.publish_threads();
+        for thread in &this.threads {
+            METRICS.set_worker(thread.index(), thread.handle.counters.clone());
+        }
+        METRICS.truncate_workers(this.threads.len());
+
+        tracing::info!("Reloaded code");
+        Ok(())
+    }
+
+    async fn shutdown(&self, timeout: Option<Duration>) -> crate::server::ShutdownOutcome {
         tracing::info!("Shutting down...");
 
-        let mut this = self.lock().await;
+        let mut this = self.runner.lock().await;
         this.shut_down = true;
+        this.retire_replacements(0);
+        this.publish_threads();
         for thread in &this.threads {
             if !thread.is_finished() {
-                _ = thread.channel.send(ControlMessage::Shutdown);
+                thread.send(ControlMessage::Shutdown);
             }
         }
         // Drop the lock handle so incoming requests can receive an
@@ -201,19 +843,26 @@ impl<H: RequestHandler + Copy + Unpin> crate::server::Runner for SharedSingleRun
         drop(this);
 
         let shutdown_started = Instant::now();
+        let mut outcome = crate::server::ShutdownOutcome::Completed;
 
         loop {
-            let this = self.lock().await;
-            if this.threads.iter().any(|t| !t.is_finished()) {
+            let this = self.runner.lock().await;
+            let all_threads = this.threads.iter().chain(&this.retired_threads);
+            // Unresponsive threads may never finish
+            if all_threads
+                .clone()
+                .any(|t| !t.is_finished() && !t.is_unresponsive())
+            {
                 if let Some(timeout) = timeout {
                     if shutdown_started.elapsed() >= timeout {
                         tracing::warn!(
                             "Clean shutdown timeout was reached before all \
                             requests could finish processing"
                         );
-                        for t in &this.threads {
+                        outcome = crate::server::ShutdownOutcome::TimedOut;
+                        for t in all_threads {
                             if !t.is_finished() {
-                                _ = t.channel.send(ControlMessage::Terminate);
+                                t.send(ControlMessage::Terminate);
                             }
                         }
                         break;
@@ -231,22 +880,16 @@ impl<H: RequestHandler + Copy + Unpin> crate::server::Runner for SharedSingleRun
             "Shutdown completed in {} seconds",
             shutdown_started.elapsed().as_secs()
         );
+        outcome
     }
 }
 
-struct IncrementGuard {
-    value: Arc<AtomicI32>,
-}
-
-impl IncrementGuard {
-    fn new(value: Arc<AtomicI32>) -> Self {
-        value.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-        Self { value }
-    }
-}
-
-impl Drop for IncrementGuard {
-    fn drop(&mut self) {
-        self.value.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+fn service_unavailable(message: &'static str, retry_later: bool) -> hyper::Response<hyper::Body> {
+    let mut response = hyper::Response::builder().status(503);
+    if retry_later {
+        response = response.header(hyper::header::RETRY_AFTER, SHED_RETRY_AFTER);
     }
+    response
+        .body(hyper::Body::from(message))
+        .expect("Failed to construct 503 response")
 }
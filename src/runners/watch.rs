@@ -1,109 +1,195 @@
-// TODO: get watch mode working again
-
-// use std::{path::PathBuf, sync::Arc};
-
-// use anyhow::bail;
-// use tokio::sync::Mutex;
-
-// use crate::request_handlers::{RequestHandler, UserCode};
-
-// use super::single::{SharedSingleRunner, SingleRunner};
-
-// /// Wraps an [`SingleRunner`] with auto-reload capabilities.
-// ///
-// /// Will auto-reload the JS code, and re-initialize the runner on changes.
-// #[derive(Clone)]
-// pub struct WatchRunner {
-//     state: Arc<State>,
-// }
-
-// struct State {
-//     js_path: PathBuf,
-//     handler: Box<dyn RequestHandler>,
-//     max_js_threads: usize,
-//     mutable: Mutex<Option<MutableState>>,
-// }
-
-// struct MutableState {
-//     contents: String,
-//     runner: SharedSingleRunner,
-// }
-
-// // TODO: support watching modules. This is complicated by the fact that we must
-// // watch all modules loaded in after the entry module was loaded as well.
-// impl WatchRunner {
-//     pub fn new(
-//         handler: Box<dyn RequestHandler>,
-//         js_path: PathBuf,
-//         script_mode: bool,
-//         max_js_threads: usize,
-//     ) -> anyhow::Result<Self> {
-//         if !script_mode {
-//             bail!("Watch mode is not compatible with module mode yet. Use --script to run in script mode instead.");
-//         }
-//         Ok(Self {
-//             state: Arc::new(State {
-//                 handler,
-//                 js_path,
-//                 max_js_threads,
-//                 mutable: Mutex::new(None),
-//             }),
-//         })
-//     }
-
-//     async fn acquire_runner(&self) -> Result<SharedSingleRunner, anyhow::Error> {
-//         // WASIX does not support inotify file watching APIs, so we naively
-//         // load the full file contents on every request.
-//         //
-//         // This is slow, but this should be sufficient for debugging.
-//         //
-//         // We should investigate just going by the file modification time.
-//         // but loading the full contents is more reliable.
-
-//         let mut mutable = self.state.mutable.lock().await;
-
-//         let (contents, file_name) = match UserCode::from_path(&self.state.js_path, true)
-//             .await
-//             .unwrap()
-//         {
-//             UserCode::Script { code, file_name } => (code, file_name),
-//             _ => unreachable!(),
-//         };
-
-//         if let Some(mutable) = mutable.as_mut() {
-//             if mutable.contents == contents {
-//                 return Ok(mutable.runner.clone());
-//             }
-//         }
-
-//         tracing::info!(path=%self.state.js_path.display(), "reloaded application code");
-
-//         let runner = SingleRunner::new_request_handler(
-//             self.state.handler.clone(),
-//             self.state.max_js_threads,
-//             UserCode::Script {
-//                 code: contents.clone(),
-//                 file_name,
-//             },
-//         );
-//         *mutable = Some(MutableState {
-//             contents,
-//             runner: runner.clone(),
-//         });
-
-//         Ok(runner)
-//     }
-// }
-
-// #[async_trait::async_trait]
-// impl crate::server::Runner for WatchRunner {
-//     async fn handle(
-//         &self,
-//         addr: std::net::SocketAddr,
-//         req: http::request::Parts,
-//         body: hyper::Body,
-//     ) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
-//         let runner = self.acquire_runner().await?;
-//         runner.handle(addr, req, body).await
-//     }
-// }
+//! Watch mode: the files making up the user code are polled for changes, and
+//! the runner is reloaded whenever one of them changes. Polling is used
+//! because WASIX doesn't support inotify or similar file watching APIs.
+//!
+//! Module mode code may be spread over many files. Every module the module
+//! loader of any worker thread loads is recorded, and watched from then on.
+//! Modules that are no longer imported stay watched, which at worst causes a
+//! reload too many.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use ion::{
+    module::{Module, ModuleData, ModuleLoader, ModuleRequest},
+    Context, Object, ResultExc, Value,
+};
+use mozjs::jsapi::{GetModulePrivate, JSObject};
+
+use crate::{
+    request_handlers::{cloudflare, UserCode, UserCodeSource},
+    server::BoxedDynRunner,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Every module file loaded by a module loader in this process.
+static LOADED_MODULES: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// The modification time of every watched file, or `None` if it doesn't
+/// exist (yet).
+type Snapshot = BTreeMap<PathBuf, Option<SystemTime>>;
+
+/// Wraps a module loader to record the path of every module it resolves,
+/// including dynamic imports.
+#[derive(Default)]
+pub(super) struct RecordingLoader<L: ModuleLoader> {
+    inner: L,
+}
+
+impl<L: ModuleLoader> ModuleLoader for RecordingLoader<L> {
+    fn resolve<'cx>(
+        &mut self,
+        cx: &'cx Context,
+        private: &Value,
+        request: &ModuleRequest,
+    ) -> ResultExc<Module<'cx>> {
+        match self.inner.resolve(cx, private, request) {
+            Ok(module) => {
+                let private =
+                    Value::from(cx.root(unsafe { GetModulePrivate(module.module_object()) }));
+                if let Some(path) = ModuleData::from_private(cx, &private).and_then(|d| d.path) {
+                    record_module(Path::new(&path));
+                }
+                Ok(module)
+            }
+            Err(e) => {
+                // Watch the file the import refers to, so the code is
+                // reloaded once it's created
+                let referrer = ModuleData::from_private(cx, private).and_then(|d| d.path);
+                if let (Some(referrer), Ok(specifier)) =
+                    (referrer, request.specifier(cx).to_owned(cx))
+                {
+                    let dir = Path::new(&referrer).parent().unwrap_or(Path::new("/"));
+                    if specifier.starts_with("./")
+                        || specifier.starts_with("../")
+                        || specifier.starts_with('/')
+                    {
+                        record_module(&dir.join(specifier));
+                    }
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn register<'cx>(
+        &mut self,
+        cx: &'cx Context,
+        module: *mut JSObject,
+        request: &ModuleRequest,
+    ) -> ResultExc<Module<'cx>> {
+        self.inner.register(cx, module, request)
+    }
+
+    fn metadata(&self, cx: &Context, private: &Value, meta: &Object) -> ResultExc<()> {
+        self.inner.metadata(cx, private, meta)
+    }
+}
+
+fn record_module(path: &Path) {
+    LOADED_MODULES.lock().unwrap().insert(normalize(path));
+}
+
+pub async fn reload_on_changes(source: UserCodeSource, runner: BoxedDynRunner) {
+    let Some(mut snapshot) = take_snapshot(&source).await else {
+        return;
+    };
+    tracing::info!(
+        "Watching {} files for changes",
+        snapshot.values().filter(|m| m.is_some()).count()
+    );
+
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+
+        let Some(current) = take_snapshot(&source).await else {
+            return;
+        };
+        // Files that only just started being watched, because they were
+        // loaded for the first time, don't count as changes
+        let changed = current
+            .iter()
+            .any(|(path, modified)| snapshot.get(path).is_some_and(|m| m != modified));
+        snapshot = current;
+        if !changed {
+            continue;
+        }
+
+        tracing::info!("Code changed, reloading");
+        if let Err(e) = runner.reload().await {
+            tracing::error!("Failed to reload code, the previous version is still being served");
+            println!("{e:?}");
+        }
+    }
+}
+
+/// Looking at the files blocks, so it's done off the async runtime. Only
+/// fails if the runtime is shutting down.
+async fn take_snapshot(source: &UserCodeSource) -> Option<Snapshot> {
+    let source = source.clone();
+    tokio::task::spawn_blocking(move || {
+        watched_files(&source)
+            .into_iter()
+            .map(|path| {
+                let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+                (path, modified)
+            })
+            .collect()
+    })
+    .await
+    .ok()
+}
+
+fn watched_files(source: &UserCodeSource) -> BTreeSet<PathBuf> {
+    let mut files = LOADED_MODULES.lock().unwrap().clone();
+
+    // The entry point itself may be missing for a moment while an editor
+    // replaces it, in which case we keep watching its path.
+    let user_code = match source.load() {
+        Ok(user_code) => user_code,
+        Err(_) => {
+            files.insert(source.path.clone());
+            return files;
+        }
+    };
+
+    match user_code {
+        UserCode::Script { .. } => {
+            files.insert(source.path.clone());
+        }
+        UserCode::Module(path) => {
+            files.insert(normalize(&path));
+        }
+        UserCode::Directory(dir) => {
+            files.insert(dir.join("_routes.json"));
+            // Watch all candidates, so a _worker.js that shows up later or
+            // takes precedence over the current one is picked up too.
+            for candidate in cloudflare::WORKER_JS_SEARCH_PATHS {
+                files.insert(dir.join(candidate));
+            }
+        }
+    }
+
+    files
+}
+
+/// Resolves `.` and `..` components without touching the file system, so
+/// files that don't exist yet can be watched too.
+pub(super) fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            c => normalized.push(c),
+        }
+    }
+    normalized
+}
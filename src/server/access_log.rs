@@ -0,0 +1,489 @@
+//! Per-request access logging, in the Common or Combined Log Format or as
+//! JSON lines. Log lines are written once the response body has been sent,
+//! so they include the response size and the total duration. Writing is
+//! left to a dedicated thread, so a slow disk never holds up a request.
+
+use std::{
+    fmt::Write as _,
+    fs::OpenOptions,
+    io::{BufWriter, Write},
+    net::IpAddr,
+    path::PathBuf,
+    pin::Pin,
+    sync::{mpsc, Arc},
+    task::{Context, Poll},
+    thread::JoinHandle,
+    time::{Instant, SystemTime},
+};
+
+use anyhow::Context as _;
+use hyper::{
+    body::{Bytes, HttpBody, SizeHint},
+    Body, Request, Response,
+};
+
+use super::WorkerIndex;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum AccessLogFormat {
+    /// The Common Log Format, followed by the request ID, the worker thread
+    /// index and the duration in milliseconds.
+    Common,
+    /// The Combined Log Format, which adds the referer and user agent to the
+    /// Common Log Format, followed by the same fields.
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+#[derive(Clone, Debug)]
+pub struct AccessLogConfig {
+    pub format: AccessLogFormat,
+    /// File to append log lines to. Logs go to stdout if not set.
+    pub path: Option<PathBuf>,
+}
+
+pub struct AccessLog {
+    config: AccessLogConfig,
+    sender: Option<mpsc::Sender<Message>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+enum Message {
+    Line(String),
+    Reopen(Box<dyn Write + Send>),
+}
+
+impl AccessLog {
+    pub fn open(config: AccessLogConfig) -> anyhow::Result<Self> {
+        let writer = open_writer(&config)?;
+        let (sender, receiver) = mpsc::channel();
+        let thread = std::thread::Builder::new()
+            .name("access-log".into())
+            .spawn(move || write_lines(writer, receiver))
+            .context("Failed to start access log thread")?;
+
+        Ok(Self {
+            config,
+            sender: Some(sender),
+            thread: Some(thread),
+        })
+    }
+
+    /// Reopens the log file, so logs go to a new file after the old one was
+    /// moved away by log rotation.
+    pub fn reopen(&self) -> anyhow::Result<()> {
+        if self.config.path.is_some() {
+            self.send(Message::Reopen(open_writer(&self.config)?));
+        }
+        Ok(())
+    }
+
+    fn write(&self, entry: &Entry, bytes: u64) {
+        let line = match self.config.format {
+            AccessLogFormat::Common => entry.to_clf(bytes, false),
+            AccessLogFormat::Combined => entry.to_clf(bytes, true),
+            AccessLogFormat::Json => entry.to_json(bytes),
+        };
+
+        self.send(Message::Line(line));
+    }
+
+    fn send(&self, message: Message) {
+        if let Some(sender) = &self.sender {
+            // The thread only goes away if writing panicked
+            _ = sender.send(message);
+        }
+    }
+}
+
+impl Drop for AccessLog {
+    fn drop(&mut self) {
+        // Wait for the lines that are still queued to be written out
+        drop(self.sender.take());
+        if let Some(thread) = self.thread.take() {
+            _ = thread.join();
+        }
+    }
+}
+
+/// Writes lines as they come in, flushing whenever it runs out of them, so
+/// lines are batched up under load but never held back for long.
+fn write_lines(writer: Box<dyn Write + Send>, receiver: mpsc::Receiver<Message>) {
+    let mut writer = BufWriter::new(writer);
+    while let Ok(message) = receiver.recv() {
+        let mut res = handle_message(&mut writer, message);
+        while let (Ok(()), Ok(message)) = (&res, receiver.try_recv()) {
+            res = handle_message(&mut writer, message);
+        }
+        if let Err(e) = res.and_then(|()| writer.flush()) {
+            tracing::warn!(error = %e, "Failed to write access log");
+        }
+    }
+}
+
+fn handle_message(
+    writer: &mut BufWriter<Box<dyn Write + Send>>,
+    message: Message,
+) -> std::io::Result<()> {
+    match message {
+        Message::Line(line) => writer.write_all(line.as_bytes()),
+        Message::Reopen(new_writer) => {
+            let res = writer.flush();
+            *writer = BufWriter::new(new_writer);
+            res
+        }
+    }
+}
+
+fn open_writer(config: &AccessLogConfig) -> anyhow::Result<Box<dyn Write + Send>> {
+    match &config.path {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open access log '{}'", path.display()))?;
+            Ok(Box::new(file))
+        }
+        None => Ok(Box::new(std::io::stdout())),
+    }
+}
+
+#[cfg(unix)]
+pub async fn reopen_on_sighup(log: Arc<AccessLog>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            tracing::warn!(error = %e, "Failed to listen for SIGHUP, access log won't be reopened");
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        match log.reopen() {
+            Ok(()) => tracing::debug!("Reopened access log"),
+            Err(e) => tracing::error!(error = format!("{e:#}"), "Failed to reopen access log"),
+        }
+    }
+}
+
+/// What we know about a request, collected when it comes in.
+pub struct Entry {
+    time: SystemTime,
+    started: Instant,
+    client: IpAddr,
+    method: http::Method,
+    target: String,
+    version: http::Version,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    request_id: String,
+    status: u16,
+    worker: Option<usize>,
+}
+
+impl Entry {
+    pub fn new(req: &Request<Body>, client: IpAddr, request_id: String) -> Self {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+        };
+
+        Self {
+            time: SystemTime::now(),
+            started: Instant::now(),
+            client,
+            method: req.method().clone(),
+            target: req
+                .uri()
+                .path_and_query()
+                .map_or_else(|| "/".to_string(), |p| p.to_string()),
+            version: req.version(),
+            referer: header(http::header::REFERER),
+            user_agent: header(http::header::USER_AGENT),
+            request_id,
+            status: 0,
+            worker: None,
+        }
+    }
+
+    fn to_clf(&self, bytes: u64, combined: bool) -> String {
+        let mut line = format!(
+            "{} - - [{}] \"{} {} {:?}\" {} ",
+            self.client,
+            clf_time(self.time),
+            self.method,
+            escape(&self.target),
+            self.version,
+            self.status,
+        );
+        if bytes == 0 {
+            line.push('-');
+        } else {
+            _ = write!(line, "{bytes}");
+        }
+
+        if combined {
+            let referer = self.referer.as_deref().unwrap_or("-");
+            let user_agent = self.user_agent.as_deref().unwrap_or("-");
+            _ = write!(line, " \"{}\" \"{}\"", escape(referer), escape(user_agent));
+        }
+
+        _ = match self.worker {
+            Some(worker) => write!(line, " {} {worker}", self.request_id),
+            None => write!(line, " {} -", self.request_id),
+        };
+        _ = writeln!(line, " {}", self.started.elapsed().as_millis());
+        line
+    }
+
+    fn to_json(&self, bytes: u64) -> String {
+        let mut line = serde_json::json!({
+            "time": rfc3339_time(self.time),
+            "client": self.client.to_string(),
+            "method": self.method.as_str(),
+            "path": self.target,
+            "protocol": format!("{:?}", self.version),
+            "status": self.status,
+            "bytes": bytes,
+            "duration_ms": self.started.elapsed().as_secs_f64() * 1000.0,
+            "referer": self.referer,
+            "user_agent": self.user_agent,
+            "request_id": self.request_id,
+            "worker": self.worker,
+        })
+        .to_string();
+        line.push('\n');
+        line
+    }
+}
+
+/// A response body that counts the bytes sent, and writes the access log
+/// line once it's dropped, which is when the response is complete or the
+/// client went away.
+pub struct LoggedBody {
+    inner: Body,
+    bytes: u64,
+    log: Option<(Arc<AccessLog>, Entry)>,
+}
+
+impl LoggedBody {
+    pub fn wrap(res: Response<Body>, log: Option<(Arc<AccessLog>, Entry)>) -> Response<LoggedBody> {
+        let log = log.map(|(log, mut entry)| {
+            entry.status = res.status().as_u16();
+            entry.worker = res.extensions().get::<WorkerIndex>().map(|w| w.0);
+            (log, entry)
+        });
+
+        res.map(|inner| LoggedBody {
+            inner,
+            bytes: 0,
+            log,
+        })
+    }
+}
+
+impl HttpBody for LoggedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_data(cx);
+        if let Poll::Ready(Some(Ok(ref data))) = res {
+            this.bytes += data.len() as u64;
+        }
+        res
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        if let Some((log, entry)) = self.log.take() {
+            log.write(&entry, self.bytes);
+        }
+    }
+}
+
+/// Escapes quotes, backslashes and control characters, so values can't
+/// break out of their quoted field.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c if c.is_control() => _ = write!(escaped, "\\x{:02x}", c as u32),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Formats a time as in `10/Oct/2000:13:55:36 +0000`. Times are always
+/// logged in UTC.
+fn clf_time(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second, _) = utc_parts(time);
+    format!(
+        "{day:02}/{}/{year}:{hour:02}:{minute:02}:{second:02} +0000",
+        MONTHS[month as usize - 1]
+    )
+}
+
+/// Formats a time as in `2000-10-10T13:55:36.123Z`.
+fn rfc3339_time(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second, millis) = utc_parts(time);
+    format!("{year}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}.{millis:03}Z")
+}
+
+fn utc_parts(time: SystemTime) -> (i64, u32, u32, u32, u32, u32, u32) {
+    let since_epoch = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = since_epoch.as_secs() as i64;
+    let (days, secs_of_day) = (secs.div_euclid(86400), secs.rem_euclid(86400) as u32);
+
+    // Howard Hinnant's days_from_civil, in reverse; see
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn time(secs: u64, millis: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn splits_times_into_utc_parts() {
+        assert_eq!(utc_parts(time(0, 0)), (1970, 1, 1, 0, 0, 0, 0));
+        // 2000-02-29T12:34:56.789Z, a leap day in a leap century
+        assert_eq!(
+            utc_parts(time(951_827_696, 789)),
+            (2000, 2, 29, 12, 34, 56, 789)
+        );
+        assert_eq!(
+            utc_parts(time(1_735_689_599, 0)),
+            (2024, 12, 31, 23, 59, 59, 0)
+        );
+        assert_eq!(utc_parts(time(1_735_689_600, 0)), (2025, 1, 1, 0, 0, 0, 0));
+        // Times before the epoch are clamped to it
+        assert_eq!(
+            utc_parts(SystemTime::UNIX_EPOCH - Duration::from_secs(1)),
+            (1970, 1, 1, 0, 0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn formats_times() {
+        assert_eq!(clf_time(time(971_186_136, 0)), "10/Oct/2000:13:55:36 +0000");
+        assert_eq!(clf_time(time(0, 0)), "01/Jan/1970:00:00:00 +0000");
+        assert_eq!(
+            rfc3339_time(time(971_186_136, 123)),
+            "2000-10-10T13:55:36.123Z"
+        );
+    }
+
+    #[test]
+    fn escapes_quoted_fields() {
+        assert_eq!(escape("curl/8.0"), "curl/8.0");
+        assert_eq!(escape(r#"a "quoted" \ value"#), r#"a \"quoted\" \\ value"#);
+        assert_eq!(escape("line\nbreak\t\x7f"), "line\\x0abreak\\x09\\x7f");
+        assert_eq!(escape("héllo"), "héllo");
+    }
+
+    #[test]
+    fn writes_and_reopens_log_file() {
+        let dir = std::env::temp_dir().join(format!("winterjs-access-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let rotated = dir.join("access.log.1");
+
+        let log = AccessLog::open(AccessLogConfig {
+            format: AccessLogFormat::Common,
+            path: Some(path.clone()),
+        })
+        .unwrap();
+        let req = Request::builder()
+            .uri("/path?query")
+            .body(Body::empty())
+            .unwrap();
+        let mut entry = Entry::new(&req, [127, 0, 0, 1].into(), "id".into());
+        entry.status = 200;
+
+        log.write(&entry, 5);
+        // Log rotation moves the file away, then asks for it to be reopened
+        while std::fs::read_to_string(&path).unwrap().is_empty() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        std::fs::rename(&path, &rotated).unwrap();
+        log.reopen().unwrap();
+        log.write(&entry, 0);
+        // Dropping the log waits for queued lines to be written
+        drop(log);
+
+        let old = std::fs::read_to_string(&rotated).unwrap();
+        let new = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(old.starts_with("127.0.0.1 - - ["), "{old}");
+        assert!(
+            old.contains("] \"GET /path?query HTTP/1.1\" 200 5 id - "),
+            "{old}"
+        );
+        assert_eq!(old.lines().count(), 1);
+        assert!(new.contains("\" 200 - id - "), "{new}");
+        assert_eq!(new.lines().count(), 1);
+    }
+}
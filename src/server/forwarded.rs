@@ -0,0 +1,518 @@
+//! Resolving the original client address, scheme and host of requests that
+//! went through one or more reverse proxies, based on the `Forwarded` and
+//! `X-Forwarded-*` headers.
+
+use std::{
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context as _};
+use http::uri::{Authority, Scheme};
+
+/// An IP address range in CIDR notation, such as `10.0.0.0/8`. A single
+/// address without a prefix length is also accepted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpCidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(u32::from(net), u32::from(ip), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches<T>(net: T, ip: T, prefix_len: u8) -> bool
+where
+    T: Copy + Eq + std::ops::BitXor<Output = T> + std::ops::Shr<u32, Output = T> + From<u8>,
+{
+    let bits = std::mem::size_of::<T>() as u32 * 8;
+    let host_bits = bits - prefix_len as u32;
+    // Shifting by the full width overflows, and a zero-length prefix
+    // matches everything anyway.
+    host_bits == bits || (net ^ ip) >> host_bits == T::from(0)
+}
+
+impl FromStr for IpCidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+
+        let addr = addr
+            .parse::<IpAddr>()
+            .with_context(|| format!("Invalid IP address in '{s}'"))?;
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix_len = match prefix_len {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max_prefix_len)
+                .ok_or_else(|| anyhow!("Invalid prefix length in '{s}'"))?,
+            None => max_prefix_len,
+        };
+
+        // IPv4-mapped IPv6 ranges are matched as the IPv4 range they map
+        let canonical = addr.to_canonical();
+        if canonical.is_ipv4() && addr.is_ipv6() {
+            let prefix_len = prefix_len
+                .checked_sub(96)
+                .ok_or_else(|| anyhow!("Invalid prefix length in '{s}'"))?;
+            return Ok(Self {
+                addr: canonical,
+                prefix_len,
+            });
+        }
+
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl Display for IpCidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// The set of proxies whose forwarding headers we believe.
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies(Vec<IpCidr>);
+
+impl TrustedProxies {
+    pub fn new(ranges: Vec<IpCidr>) -> Self {
+        Self(ranges)
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|range| range.contains(ip))
+    }
+
+    /// Returns the address of the client that originally made the request.
+    /// If the peer is a trusted proxy, the forwarding headers are walked
+    /// from the closest hop backwards, and the first address that is not a
+    /// trusted proxy is returned. Addresses taken from `X-Forwarded-For`
+    /// have no port, so port 0 is used instead.
+    pub fn client_addr(&self, peer: SocketAddr, headers: &http::HeaderMap) -> SocketAddr {
+        if !self.is_trusted(peer.ip()) {
+            return peer;
+        }
+
+        let hops = if headers.contains_key(http::header::FORWARDED) {
+            forwarded_for_hops(headers)
+        } else {
+            x_forwarded_for_hops(headers)
+        };
+
+        let mut client = peer;
+        for hop in hops.into_iter().rev() {
+            match hop {
+                Some(addr) => {
+                    client = addr;
+                    if !self.is_trusted(addr.ip()) {
+                        break;
+                    }
+                }
+                // An obfuscated or malformed hop; we can't tell who came
+                // before it, so the last hop we know of is the best we have.
+                None => break,
+            }
+        }
+
+        client
+    }
+
+    /// Returns how many hops, counting back from the closest one, were
+    /// added by trusted proxies, not counting the one `client_addr` stops
+    /// at. The entries of that hop were written by the last trusted proxy.
+    fn trusted_hops(&self, hops: &[Option<SocketAddr>]) -> usize {
+        let trusted = hops
+            .iter()
+            .rev()
+            .take_while(|hop| hop.is_some_and(|addr| self.is_trusted(addr.ip())))
+            .count();
+        trusted.min(hops.len().saturating_sub(1))
+    }
+
+    /// Returns the scheme and host forwarded by the peer, if it is a trusted
+    /// proxy. Each proxy appends to the headers, and anything before the
+    /// entries of the first trusted proxy may have been made up by the
+    /// client, so the entries used are the ones of the hop `client_addr`
+    /// stops at. Invalid values are ignored.
+    pub fn forwarded_origin(&self, peer: SocketAddr, headers: &http::HeaderMap) -> ForwardedOrigin {
+        if !self.is_trusted(peer.ip()) {
+            return ForwardedOrigin::default();
+        }
+
+        let (scheme, host) = if headers.contains_key(http::header::FORWARDED) {
+            let hop = self.trusted_hops(&forwarded_for_hops(headers));
+            match forwarded_elements(headers)
+                .collect::<Vec<_>>()
+                .iter()
+                .rev()
+                .nth(hop)
+            {
+                Some(element) => (
+                    forwarded_param(element, "proto"),
+                    forwarded_param(element, "host"),
+                ),
+                None => (None, None),
+            }
+        } else {
+            let hop = self.trusted_hops(&x_forwarded_for_hops(headers));
+            (
+                nth_last_list_value(headers, "x-forwarded-proto", hop),
+                nth_last_list_value(headers, "x-forwarded-host", hop),
+            )
+        };
+
+        ForwardedOrigin {
+            scheme: scheme.and_then(|s| match s.to_ascii_lowercase().as_str() {
+                "http" => Some(Scheme::HTTP),
+                "https" => Some(Scheme::HTTPS),
+                _ => None,
+            }),
+            host: host.and_then(|h| Authority::try_from(h.as_str()).ok()),
+        }
+    }
+}
+
+/// The scheme and host the client used to reach the first trusted proxy.
+#[derive(Debug, Default)]
+pub struct ForwardedOrigin {
+    pub scheme: Option<Scheme>,
+    pub host: Option<Authority>,
+}
+
+/// Returns the `n`th value of a comma-separated list header, counting from
+/// the last one.
+fn nth_last_list_value(headers: &http::HeaderMap, name: &str, n: usize) -> Option<String> {
+    let values = headers
+        .get_all(name)
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or_default().split(','))
+        .collect::<Vec<_>>();
+    let value = values.iter().rev().nth(n)?.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+fn x_forwarded_for_hops(headers: &http::HeaderMap) -> Vec<Option<SocketAddr>> {
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or_default().split(','))
+        .map(|hop| parse_node(hop.trim()))
+        .collect()
+}
+
+fn forwarded_for_hops(headers: &http::HeaderMap) -> Vec<Option<SocketAddr>> {
+    forwarded_elements(headers)
+        .map(|element| forwarded_param(element, "for").and_then(|node| parse_node(&node)))
+        .collect()
+}
+
+/// Iterates over the comma-separated elements of all `Forwarded` headers,
+/// in order.
+fn forwarded_elements(headers: &http::HeaderMap) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(http::header::FORWARDED)
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or_default().split(','))
+}
+
+/// Extracts the value of a parameter from an element of a `Forwarded`
+/// header, e.g. `for` from `for=192.0.2.60;proto=http`.
+fn forwarded_param(element: &str, name: &str) -> Option<String> {
+    element.split(';').find_map(|pair| {
+        let (key, value) = pair.trim().split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+/// Parses a node, which may be an IPv4 address, an IPv6 address in brackets
+/// or a bare one, any of them followed by an optional port.
+fn parse_node(node: &str) -> Option<SocketAddr> {
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+
+    let ip = node.trim_start_matches('[').trim_end_matches(']');
+    ip.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderMap;
+
+    use super::*;
+
+    fn proxies(ranges: &[&str]) -> TrustedProxies {
+        TrustedProxies::new(ranges.iter().map(|r| r.parse().unwrap()).collect())
+    }
+
+    fn header_map(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn origin(proxies: &TrustedProxies, peer: &str, headers: &HeaderMap) -> (String, String) {
+        let origin = proxies.forwarded_origin(addr(peer), headers);
+        (
+            origin.scheme.map(|s| s.to_string()).unwrap_or_default(),
+            origin.host.map(|h| h.to_string()).unwrap_or_default(),
+        )
+    }
+
+    #[test]
+    fn cidr_parsing() {
+        assert_eq!(
+            "10.0.0.0/8".parse::<IpCidr>().unwrap().to_string(),
+            "10.0.0.0/8"
+        );
+        assert_eq!(
+            "192.0.2.1".parse::<IpCidr>().unwrap().to_string(),
+            "192.0.2.1/32"
+        );
+        assert_eq!(
+            "2001:db8::/32".parse::<IpCidr>().unwrap().to_string(),
+            "2001:db8::/32"
+        );
+        assert_eq!("::1".parse::<IpCidr>().unwrap().to_string(), "::1/128");
+        assert_eq!(
+            "::ffff:10.0.0.0/104".parse::<IpCidr>().unwrap().to_string(),
+            "10.0.0.0/8"
+        );
+
+        assert!("10.0.0.0/33".parse::<IpCidr>().is_err());
+        assert!("2001:db8::/129".parse::<IpCidr>().is_err());
+        assert!("::ffff:10.0.0.0/64".parse::<IpCidr>().is_err());
+        assert!("10.0.0.0/".parse::<IpCidr>().is_err());
+        assert!("example.com".parse::<IpCidr>().is_err());
+    }
+
+    #[test]
+    fn cidr_matching() {
+        let range: IpCidr = "10.0.0.0/8".parse().unwrap();
+        assert!(range.contains("10.1.2.3".parse().unwrap()));
+        assert!(!range.contains("11.0.0.1".parse().unwrap()));
+        assert!(range.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!range.contains("::ffff:11.0.0.1".parse().unwrap()));
+        assert!(!range.contains("2001:db8::1".parse().unwrap()));
+
+        let range: IpCidr = "2001:db8::/32".parse().unwrap();
+        assert!(range.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!range.contains("2001:db9::1".parse().unwrap()));
+        assert!(!range.contains("10.0.0.1".parse().unwrap()));
+
+        let range: IpCidr = "::ffff:192.0.2.0/120".parse().unwrap();
+        assert!(range.contains("192.0.2.7".parse().unwrap()));
+
+        let range: IpCidr = "0.0.0.0/0".parse().unwrap();
+        assert!(range.contains("203.0.113.1".parse().unwrap()));
+
+        let range: IpCidr = "192.0.2.1".parse().unwrap();
+        assert!(range.contains("192.0.2.1".parse().unwrap()));
+        assert!(!range.contains("192.0.2.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn node_parsing() {
+        assert_eq!(parse_node("192.0.2.1"), Some(addr("192.0.2.1:0")));
+        assert_eq!(parse_node("192.0.2.1:8080"), Some(addr("192.0.2.1:8080")));
+        assert_eq!(parse_node("[2001:db8::1]"), Some(addr("[2001:db8::1]:0")));
+        assert_eq!(
+            parse_node("[2001:db8::1]:8080"),
+            Some(addr("[2001:db8::1]:8080"))
+        );
+        assert_eq!(parse_node("2001:db8::1"), Some(addr("[2001:db8::1]:0")));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+    }
+
+    #[test]
+    fn forwarded_params() {
+        let element = r#"for="[2001:db8::1]:4711";Proto=https; host=example.com"#;
+        assert_eq!(
+            forwarded_param(element, "for").as_deref(),
+            Some("[2001:db8::1]:4711")
+        );
+        assert_eq!(forwarded_param(element, "proto").as_deref(), Some("https"));
+        assert_eq!(
+            forwarded_param(element, "host").as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(forwarded_param(element, "by"), None);
+    }
+
+    #[test]
+    fn client_addr_from_untrusted_peer() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        let headers = header_map(&[("x-forwarded-for", "192.0.2.1")]);
+        assert_eq!(
+            proxies.client_addr(addr("203.0.113.1:1234"), &headers),
+            addr("203.0.113.1:1234")
+        );
+    }
+
+    #[test]
+    fn client_addr_from_x_forwarded_for() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        let peer = addr("10.0.0.1:1234");
+
+        // The client can put anything in front, but the walk stops at the
+        // first address that isn't a trusted proxy
+        let headers = header_map(&[
+            ("x-forwarded-for", "198.51.100.1, 192.0.2.1"),
+            ("x-forwarded-for", "10.0.0.2"),
+        ]);
+        assert_eq!(proxies.client_addr(peer, &headers), addr("192.0.2.1:0"));
+
+        let headers = header_map(&[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]);
+        assert_eq!(proxies.client_addr(peer, &headers), addr("10.0.0.3:0"));
+
+        let headers = header_map(&[("x-forwarded-for", "192.0.2.1, garbage")]);
+        assert_eq!(proxies.client_addr(peer, &headers), peer);
+
+        assert_eq!(proxies.client_addr(peer, &HeaderMap::new()), peer);
+    }
+
+    #[test]
+    fn client_addr_from_forwarded() {
+        let proxies = proxies(&["10.0.0.0/8", "2001:db8::/32"]);
+        let peer = addr("10.0.0.1:1234");
+
+        let headers = header_map(&[
+            (
+                "forwarded",
+                r#"for=198.51.100.1, for="[2001:db8:cafe::17]:4711""#,
+            ),
+            ("forwarded", "for=10.0.0.2;proto=https"),
+            // Ignored, since Forwarded takes precedence
+            ("x-forwarded-for", "203.0.113.1"),
+        ]);
+        assert_eq!(proxies.client_addr(peer, &headers), addr("198.51.100.1:0"));
+
+        let headers = header_map(&[("forwarded", "for=192.0.2.43:5000, for=10.0.0.2")]);
+        assert_eq!(proxies.client_addr(peer, &headers), addr("192.0.2.43:5000"));
+    }
+
+    #[test]
+    fn origin_from_untrusted_peer() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        let headers = header_map(&[("forwarded", "for=192.0.2.1;proto=https;host=example.com")]);
+        assert_eq!(
+            origin(&proxies, "203.0.113.1:1234", &headers),
+            (String::new(), String::new())
+        );
+    }
+
+    #[test]
+    fn origin_from_forwarded() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+
+        let headers = header_map(&[("forwarded", "for=192.0.2.1;proto=https;host=example.com")]);
+        assert_eq!(
+            origin(&proxies, "10.0.0.1:1234", &headers),
+            ("https".into(), "example.com".into())
+        );
+
+        // The client made up the first element, and the proxy appended its own
+        let headers = header_map(&[(
+            "forwarded",
+            "for=198.51.100.1;proto=http;host=evil.example, \
+             for=192.0.2.1;proto=https;host=example.com",
+        )]);
+        assert_eq!(
+            origin(&proxies, "10.0.0.1:1234", &headers),
+            ("https".into(), "example.com".into())
+        );
+
+        // Through two trusted proxies, the first one saw the client's request
+        let headers = header_map(&[(
+            "forwarded",
+            "for=192.0.2.1;proto=https;host=example.com, \
+             for=10.0.0.2;proto=http;host=internal",
+        )]);
+        assert_eq!(
+            origin(&proxies, "10.0.0.1:1234", &headers),
+            ("https".into(), "example.com".into())
+        );
+
+        let headers = header_map(&[("forwarded", "for=192.0.2.1;proto=gopher;host=bad host")]);
+        assert_eq!(
+            origin(&proxies, "10.0.0.1:1234", &headers),
+            (String::new(), String::new())
+        );
+    }
+
+    #[test]
+    fn origin_from_x_forwarded() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+
+        let headers = header_map(&[
+            ("x-forwarded-for", "192.0.2.1"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "example.com"),
+        ]);
+        assert_eq!(
+            origin(&proxies, "10.0.0.1:1234", &headers),
+            ("https".into(), "example.com".into())
+        );
+
+        // The client sent its own values, which the proxy appended to
+        let headers = header_map(&[
+            ("x-forwarded-for", "198.51.100.1, 192.0.2.1"),
+            ("x-forwarded-proto", "http, https"),
+            ("x-forwarded-host", "evil.example, example.com"),
+        ]);
+        assert_eq!(
+            origin(&proxies, "10.0.0.1:1234", &headers),
+            ("https".into(), "example.com".into())
+        );
+
+        // Through two trusted proxies
+        let headers = header_map(&[
+            ("x-forwarded-for", "192.0.2.1, 10.0.0.2"),
+            ("x-forwarded-proto", "https, http"),
+            ("x-forwarded-host", "example.com"),
+            ("x-forwarded-host", "internal"),
+        ]);
+        assert_eq!(
+            origin(&proxies, "10.0.0.1:1234", &headers),
+            ("https".into(), "example.com".into())
+        );
+
+        // A proxy that sets the values, rather than appending to them
+        let headers = header_map(&[
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "example.com"),
+        ]);
+        assert_eq!(
+            origin(&proxies, "10.0.0.1:1234", &headers),
+            ("https".into(), "example.com".into())
+        );
+    }
+}
@@ -0,0 +1,493 @@
+//! Limits on how much clients may send and how long they may take to do so.
+//! Requests that break them are answered here, before they ever reach a
+//! JavaScript worker.
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use hyper::{body::HttpBody, Body, Response, StatusCode};
+use parking_lot::Mutex;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+/// Sent when a client takes too long to send the request head. hyper has no
+/// way of responding on its own, so it's written directly to the stream.
+const REQUEST_TIMEOUT_RESPONSE: &[u8] =
+    b"HTTP/1.1 408 Request Timeout\r\nconnection: close\r\ncontent-length: 0\r\n\r\n";
+
+#[derive(Clone, Debug)]
+pub struct LimitsConfig {
+    /// Largest request body to accept, in bytes.
+    pub max_body_size: Option<u64>,
+    /// Largest request head (HTTP/1) or header list (HTTP/2) to accept,
+    /// in bytes.
+    pub max_header_size: u32,
+    /// How long a client gets to send the request head, counted from when
+    /// the connection was accepted for the first request, and from the
+    /// first byte for later ones.
+    pub header_read_timeout: Option<Duration>,
+    /// How long a connection may stay open without any requests on it.
+    pub keep_alive_timeout: Option<Duration>,
+    /// Maximum number of connections served at the same time. Further
+    /// connections wait in the listen backlog until a slot frees up.
+    pub max_connections: Option<usize>,
+}
+
+/// Rejects bodies larger than `max_size`. If the size isn't known up front,
+/// as with chunked uploads, the body is still streamed to the worker, but
+/// fails with an error once more than `max_size` bytes were received. The
+/// returned [`BodyLimit`] then turns the worker's response into a 413.
+pub fn limit_body(body: Body, max_size: Option<u64>) -> Result<(Body, BodyLimit), StatusCode> {
+    let limit = BodyLimit::default();
+    let Some(max_size) = max_size else {
+        return Ok((body, limit));
+    };
+
+    let size_hint = body.size_hint();
+    if size_hint.lower() > max_size {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    if size_hint.exact().is_some() {
+        return Ok((body, limit));
+    }
+
+    let (mut sender, limited) = Body::channel();
+    let exceeded = limit.0.clone();
+    tokio::spawn(async move {
+        let mut body = body;
+        let mut received = 0u64;
+        while let Some(chunk) = body.data().await {
+            let Ok(chunk) = chunk else {
+                sender.abort();
+                return;
+            };
+            received += chunk.len() as u64;
+            if received > max_size {
+                tracing::debug!("Request body is larger than {max_size} bytes, aborting it");
+                // Set before aborting, so it's visible by the time the
+                // worker sees the error
+                exceeded.store(true, Ordering::Release);
+                sender.abort();
+                return;
+            }
+            // The receiving end is gone if the request was dropped
+            if sender.send_data(chunk).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    Ok((limited, limit))
+}
+
+/// Records whether a streamed request body went over the size limit.
+#[derive(Clone, Debug, Default)]
+pub struct BodyLimit(Arc<AtomicBool>);
+
+impl BodyLimit {
+    pub fn exceeded(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+
+    /// Whatever the worker made of a body that was cut off, the client gets
+    /// a 413 for it.
+    pub fn check<E>(&self, res: Result<Response<Body>, E>) -> Result<Response<Body>, E> {
+        if self.exceeded() {
+            Ok(error_response(StatusCode::PAYLOAD_TOO_LARGE))
+        } else {
+            res
+        }
+    }
+}
+
+pub fn error_response(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(status.canonical_reason().unwrap_or_default()))
+        .unwrap()
+}
+
+/// Keeps track of what's happening on a connection, so idle connections and
+/// slow clients can be timed out.
+#[derive(Debug)]
+pub struct ConnectionActivity {
+    in_flight: AtomicUsize,
+    requests_started: AtomicU64,
+    http2: AtomicBool,
+    last_active: Mutex<Instant>,
+    changed: tokio::sync::Notify,
+}
+
+impl ConnectionActivity {
+    pub fn new() -> Self {
+        Self {
+            in_flight: AtomicUsize::new(0),
+            requests_started: AtomicU64::new(0),
+            http2: AtomicBool::new(false),
+            last_active: Mutex::new(Instant::now()),
+            changed: tokio::sync::Notify::new(),
+        }
+    }
+
+    /// Marks the start of a request. The request lasts until the returned
+    /// guard is dropped.
+    pub fn start_request(self: &Arc<Self>) -> RequestGuard {
+        self.requests_started.fetch_add(1, Ordering::Relaxed);
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        RequestGuard(self.clone())
+    }
+
+    fn touch(&self) {
+        *self.last_active.lock() = Instant::now();
+        self.changed.notify_waiters();
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.in_flight.load(Ordering::Relaxed) > 0
+    }
+
+    pub fn is_http2(&self) -> bool {
+        self.http2.load(Ordering::Relaxed)
+    }
+
+    /// Resolves once the connection has had no requests in flight and
+    /// nothing to read for `timeout`.
+    pub async fn idle_for(&self, timeout: Duration) {
+        loop {
+            let changed = self.changed.notified();
+            let deadline = *self.last_active.lock() + timeout;
+
+            if self.is_busy() {
+                changed.await;
+                continue;
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep_until(deadline) => {
+                    if !self.is_busy() && *self.last_active.lock() + timeout <= Instant::now() {
+                        return;
+                    }
+                }
+                _ = changed => (),
+            }
+        }
+    }
+}
+
+pub struct RequestGuard(Arc<ConnectionActivity>);
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.0.touch();
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// Not known yet; decided by looking at the first bytes the client sends.
+    Unknown,
+    Http1,
+    Http2,
+}
+
+enum HeadState {
+    /// Waiting for the first byte of the next request head, which isn't
+    /// timed; the keep-alive timeout takes care of idle connections.
+    Waiting,
+    /// Reading a request head, which must be complete by the deadline.
+    Reading,
+    /// The head took too long; writing out a 408 before closing.
+    TimingOut { written: usize },
+    /// The 408 is out, the connection is done.
+    TimedOut,
+}
+
+/// Enforces the header read timeout on HTTP/1 connections, and records read
+/// activity for the keep-alive timeout.
+pub struct TimeoutStream<S> {
+    inner: S,
+    activity: Arc<ConnectionActivity>,
+    header_read_timeout: Option<Duration>,
+    protocol: Protocol,
+    requests_seen: u64,
+    state: HeadState,
+    deadline: Pin<Box<Sleep>>,
+}
+
+impl<S> TimeoutStream<S> {
+    pub fn new(
+        inner: S,
+        activity: Arc<ConnectionActivity>,
+        header_read_timeout: Option<Duration>,
+        protocol: Protocol,
+        accepted_at: Instant,
+    ) -> Self {
+        activity
+            .http2
+            .store(protocol == Protocol::Http2, Ordering::Relaxed);
+
+        // Clients that connect and then send nothing at all are timed out
+        // too, so the first head's deadline runs from the accept.
+        let (state, deadline) = match header_read_timeout {
+            Some(timeout) if protocol != Protocol::Http2 => {
+                (HeadState::Reading, accepted_at + timeout)
+            }
+            _ => (HeadState::Waiting, Instant::now()),
+        };
+
+        Self {
+            inner,
+            activity,
+            header_read_timeout,
+            protocol,
+            requests_seen: 0,
+            state,
+            deadline: Box::pin(tokio::time::sleep_until(deadline)),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> TimeoutStream<S> {
+    fn poll_write_timeout_response(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let HeadState::TimingOut { written } = &mut self.state {
+            if *written == REQUEST_TIMEOUT_RESPONSE.len() {
+                self.state = HeadState::TimedOut;
+                break;
+            }
+            let n = ready!(
+                Pin::new(&mut self.inner).poll_write(cx, &REQUEST_TIMEOUT_RESPONSE[*written..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            *written += n;
+        }
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for TimeoutStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if let HeadState::TimingOut { .. } | HeadState::TimedOut = this.state {
+            ready!(this.poll_write_timeout_response(cx))?;
+            // Report EOF, so hyper closes the connection
+            return Poll::Ready(Ok(()));
+        }
+
+        // hyper only calls the service once it has the whole head
+        let requests_started = this.activity.requests_started.load(Ordering::Relaxed);
+        if requests_started != this.requests_seen || this.activity.is_busy() {
+            this.requests_seen = requests_started;
+            this.state = HeadState::Waiting;
+        }
+
+        let filled = buf.filled().len();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                let read = &buf.filled()[filled..];
+                if read.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                this.activity.touch();
+
+                if this.protocol == Protocol::Unknown {
+                    // The h2c connection preface starts with "PRI", which
+                    // no HTTP/1 method does
+                    this.protocol = if read.starts_with(b"PRI") {
+                        this.activity.http2.store(true, Ordering::Relaxed);
+                        this.state = HeadState::Waiting;
+                        Protocol::Http2
+                    } else {
+                        Protocol::Http1
+                    };
+                }
+
+                let starts_head = matches!(this.state, HeadState::Waiting)
+                    && this.protocol == Protocol::Http1
+                    && !this.activity.is_busy();
+                if let (true, Some(timeout)) = (starts_head, this.header_read_timeout) {
+                    this.state = HeadState::Reading;
+                    this.deadline.as_mut().reset(Instant::now() + timeout);
+                }
+
+                Poll::Ready(Ok(()))
+            }
+            Poll::Pending => {
+                if let HeadState::Reading = this.state {
+                    if this.deadline.as_mut().poll(cx).is_ready() {
+                        tracing::debug!("Timed out while reading request head");
+                        this.state = HeadState::TimingOut { written: 0 };
+                        ready!(this.poll_write_timeout_response(cx))?;
+                        return Poll::Ready(Ok(()));
+                    }
+                }
+                Poll::Pending
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TimeoutStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    async fn read_body(mut body: Body) -> Result<Vec<u8>, hyper::Error> {
+        let mut data = vec![];
+        while let Some(chunk) = body.data().await {
+            data.extend_from_slice(&chunk?);
+        }
+        Ok(data)
+    }
+
+    /// A body of unknown length, like a chunked upload.
+    fn streamed_body(chunks: &'static [&'static [u8]]) -> Body {
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for chunk in chunks {
+                if sender.send_data(Bytes::from_static(chunk)).await.is_err() {
+                    return;
+                }
+            }
+        });
+        body
+    }
+
+    #[tokio::test]
+    async fn rejects_large_content_length() {
+        let res = limit_body(Body::from("too large"), Some(4));
+        assert_eq!(res.err(), Some(StatusCode::PAYLOAD_TOO_LARGE));
+
+        let (body, limit) = limit_body(Body::from("fine"), Some(4)).unwrap();
+        assert_eq!(read_body(body).await.unwrap(), b"fine");
+        assert!(!limit.exceeded());
+    }
+
+    #[tokio::test]
+    async fn streams_small_bodies() {
+        let (body, limit) = limit_body(streamed_body(&[b"ab", b"cd"]), Some(4)).unwrap();
+        assert_eq!(read_body(body).await.unwrap(), b"abcd");
+        assert!(!limit.exceeded());
+        assert_eq!(
+            limit
+                .check::<()>(Ok(Response::new(Body::empty())))
+                .unwrap()
+                .status(),
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn cuts_off_large_streamed_bodies() {
+        let (body, limit) = limit_body(streamed_body(&[b"ab", b"cd", b"ef"]), Some(4)).unwrap();
+        assert!(read_body(body).await.is_err());
+        assert!(limit.exceeded());
+
+        // Whatever the worker made of the error, the client gets a 413
+        let worker_error = error_response(StatusCode::INTERNAL_SERVER_ERROR);
+        let res = limit.check::<()>(Ok(worker_error)).unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let res = limit
+            .check(Err(anyhow::anyhow!("JavaScript failed")))
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn times_out_silent_clients_from_accept() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let accepted_at = Instant::now() - Duration::from_millis(40);
+        let mut stream = TimeoutStream::new(
+            server,
+            Arc::new(ConnectionActivity::new()),
+            Some(Duration::from_millis(50)),
+            Protocol::Unknown,
+            accepted_at,
+        );
+
+        // The client never sends anything, so the deadline has to count
+        // from the accept rather than the first byte
+        let mut buf = [0; 16];
+        let read = tokio::time::timeout(Duration::from_millis(500), stream.read(&mut buf))
+            .await
+            .expect("the head should time out");
+        assert_eq!(read.unwrap(), 0);
+        assert!(Instant::now() - accepted_at < Duration::from_millis(500));
+
+        drop(stream);
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, REQUEST_TIMEOUT_RESPONSE);
+    }
+
+    #[tokio::test]
+    async fn does_not_time_out_http2() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut stream = TimeoutStream::new(
+            server,
+            Arc::new(ConnectionActivity::new()),
+            Some(Duration::from_millis(10)),
+            Protocol::Unknown,
+            Instant::now(),
+        );
+
+        client.write_all(b"PRI * HTTP/2.0\r\n").await.unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 16);
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        client.write_all(b"\r\nSM\r\n\r\n").await.unwrap();
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 8);
+    }
+}
@@ -0,0 +1,459 @@
+use std::{
+    fmt::Display,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+};
+
+#[cfg(unix)]
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context as _};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+};
+
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+/// An address to accept connections on, as given to `--listen`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    /// Plain HTTP over TCP, written as `tcp://<ip>:<port>`.
+    Tcp(SocketAddr),
+    /// HTTPS over TCP, written as `tls://<ip>:<port>`.
+    Tls(SocketAddr),
+    /// Plain HTTP over a Unix domain socket, written as `unix:<path>`.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    pub fn is_tls(&self) -> bool {
+        matches!(self, Self::Tls(_))
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_socket_addr = |addr: &str| {
+            addr.parse::<SocketAddr>()
+                .with_context(|| format!("Invalid socket address '{addr}'"))
+        };
+
+        if let Some(addr) = s.strip_prefix("tcp://") {
+            Ok(Self::Tcp(parse_socket_addr(addr)?))
+        } else if let Some(addr) = s.strip_prefix("tls://") {
+            Ok(Self::Tls(parse_socket_addr(addr)?))
+        } else if let Some(path) = s.strip_prefix("unix:") {
+            // Accept both unix:/path and unix:///path
+            let path = path.strip_prefix("//").unwrap_or(path);
+            if path.is_empty() {
+                return Err(anyhow!("Missing socket path in '{s}'"));
+            }
+
+            #[cfg(unix)]
+            return Ok(Self::Unix(PathBuf::from(path)));
+
+            #[cfg(not(unix))]
+            return Err(anyhow!(
+                "Unix domain sockets are not supported on this platform"
+            ));
+        } else {
+            // Be lenient and accept bare addresses as well
+            parse_socket_addr(s).map(Self::Tcp).map_err(|_| {
+                anyhow!(
+                    "Invalid listen address '{s}', expected one of tcp://<ip>:<port>, \
+                    tls://<ip>:<port> or unix:<path>"
+                )
+            })
+        }
+    }
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "tcp://{addr}"),
+            Self::Tls(addr) => write!(f, "tls://{addr}"),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Parses a Unix file mode given in octal notation, such as `660`.
+pub fn parse_unix_socket_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| format!("Invalid file mode '{s}', expected an octal value such as 660"))
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    /// The path is set if we created the socket file ourselves, in which
+    /// case it is removed once the listener is dropped.
+    #[cfg(unix)]
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+impl Listener {
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    pub async fn bind(addr: &ListenAddr, unix_socket_mode: Option<u32>) -> anyhow::Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) | ListenAddr::Tls(addr) => Ok(Self::Tcp(
+                TcpListener::bind(addr)
+                    .await
+                    .with_context(|| format!("Failed to bind to '{addr}'"))?,
+            )),
+
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+                // A socket file left behind by a previous run would make
+                // binding fail, but we don't want to remove anything else,
+                // including the socket of an instance that's still running.
+                if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                    if metadata.file_type().is_socket() {
+                        match std::os::unix::net::UnixStream::connect(path) {
+                            Ok(_) => anyhow::bail!(
+                                "Socket '{}' is in use by another process",
+                                path.display()
+                            ),
+                            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                                std::fs::remove_file(path).with_context(|| {
+                                    format!("Failed to remove stale socket '{}'", path.display())
+                                })?;
+                            }
+                            // Binding reports the error if the file is in the way
+                            Err(_) => (),
+                        }
+                    }
+                }
+
+                let listener = UnixListener::bind(path)
+                    .with_context(|| format!("Failed to bind to '{}'", path.display()))?;
+
+                if let Some(mode) = unix_socket_mode {
+                    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+                        .with_context(|| {
+                            format!("Failed to set permissions on '{}'", path.display())
+                        })?;
+                }
+
+                Ok(Self::Unix(listener, Some(path.clone())))
+            }
+        }
+    }
+
+    /// Accepts a new connection. Peers connecting through a Unix domain
+    /// socket are on the same host, so they are reported as coming from
+    /// 127.0.0.1.
+    pub async fn accept(&self) -> io::Result<(Stream, SocketAddr)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), addr))
+            }
+
+            #[cfg(unix)]
+            Self::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((
+                    Stream::Unix(stream),
+                    (std::net::Ipv4Addr::LOCALHOST, 0).into(),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Self::Unix(_, Some(path)) = self {
+            _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// The first file descriptor passed in by systemd, see sd_listen_fds(3).
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: std::os::fd::RawFd = 3;
+
+/// The listening sockets passed in through systemd's socket activation
+/// protocol, see [`SystemdListenFds::take_from_env`].
+#[cfg(unix)]
+#[derive(Clone, Debug)]
+pub struct SystemdListenFds {
+    count: std::os::fd::RawFd,
+    names: Vec<String>,
+}
+
+#[cfg(unix)]
+impl SystemdListenFds {
+    /// Reads and clears `LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES`.
+    /// Modifying the environment isn't safe once other threads are running,
+    /// so this must be called before any are spawned.
+    pub fn take_from_env() -> anyhow::Result<Option<Self>> {
+        let (Ok(pid), Ok(fds)) = (std::env::var("LISTEN_PID"), std::env::var("LISTEN_FDS")) else {
+            return Ok(None);
+        };
+        let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+
+        // The variables are meant for us only, so don't leak them to anything
+        // else that may inspect the environment.
+        for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            std::env::remove_var(var);
+        }
+
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            tracing::debug!("Ignoring LISTEN_FDS, since LISTEN_PID does not match our own PID");
+            return Ok(None);
+        }
+
+        let count = fds
+            .parse::<std::os::fd::RawFd>()
+            .with_context(|| format!("Invalid value in LISTEN_FDS: '{fds}'"))?;
+        if count <= 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            count,
+            names: names.split(':').map(ToOwned::to_owned).collect(),
+        }))
+    }
+
+    /// Takes over the sockets. Sockets named `tls` or `https` through
+    /// `FileDescriptorName=` are served with TLS, all others with plain HTTP.
+    pub(super) fn adopt(&self) -> anyhow::Result<Vec<(ListenAddr, Listener)>> {
+        let mut listeners = vec![];
+        for (index, fd) in (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + self.count).enumerate() {
+            let name = self
+                .names
+                .get(index)
+                .map(String::as_str)
+                .unwrap_or_default();
+            let (addr, listener) = adopt_listener_fd(fd, name).with_context(|| {
+                format!("Failed to adopt socket-activated file descriptor {fd}")
+            })?;
+            tracing::info!(fd, name, listen = %addr, "Using socket-activated file descriptor {fd}");
+            listeners.push((addr, listener));
+        }
+
+        Ok(listeners)
+    }
+}
+
+#[cfg(unix)]
+fn adopt_listener_fd(fd: std::os::fd::RawFd, name: &str) -> anyhow::Result<(ListenAddr, Listener)> {
+    use std::os::fd::FromRawFd;
+
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    // Safety: storage is large enough for any socket address, and
+    // getsockname fails gracefully if fd is not a valid socket.
+    if unsafe {
+        libc::getsockname(
+            fd,
+            &mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    } != 0
+    {
+        return Err(io::Error::last_os_error()).context("Not a socket");
+    }
+
+    // Socket-activated file descriptors are inherited on purpose, but any
+    // process we may spawn shouldn't inherit them again.
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error()).context("Failed to set FD_CLOEXEC");
+    }
+
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET | libc::AF_INET6 => {
+            // Safety: we checked that fd is an IP socket, and systemd
+            // hands over its ownership to us.
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            let local_addr = listener.local_addr()?;
+            let addr = match name {
+                "tls" | "https" => ListenAddr::Tls(local_addr),
+                _ => ListenAddr::Tcp(local_addr),
+            };
+            Ok((addr, Listener::Tcp(TcpListener::from_std(listener)?)))
+        }
+
+        libc::AF_UNIX => {
+            // Safety: same as above
+            let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            let path = listener
+                .local_addr()?
+                .as_pathname()
+                .map(|p| p.to_path_buf())
+                .unwrap_or_default();
+            // systemd owns the socket file, so we must not remove it
+            Ok((
+                ListenAddr::Unix(path),
+                Listener::Unix(UnixListener::from_std(listener)?, None),
+            ))
+        }
+
+        family => anyhow::bail!("Unsupported socket address family {family}"),
+    }
+}
+
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+            #[cfg(unix)]
+            Self::Unix(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Self::Tcp(s) => s.is_write_vectored(),
+            #[cfg(unix)]
+            Self::Unix(s) => s.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(unix)]
+            Self::Unix(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(unix)]
+            Self::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tcp_addresses() {
+        let addr = "127.0.0.1:8080".parse::<SocketAddr>().unwrap();
+        assert_eq!(
+            "tcp://127.0.0.1:8080".parse::<ListenAddr>().unwrap(),
+            ListenAddr::Tcp(addr)
+        );
+        assert_eq!(
+            "127.0.0.1:8080".parse::<ListenAddr>().unwrap(),
+            ListenAddr::Tcp(addr)
+        );
+        assert_eq!(
+            "tcp://[::1]:8080".parse::<ListenAddr>().unwrap(),
+            ListenAddr::Tcp("[::1]:8080".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn parses_tls_addresses() {
+        let addr = "tls://0.0.0.0:443".parse::<ListenAddr>().unwrap();
+        assert_eq!(addr, ListenAddr::Tls("0.0.0.0:443".parse().unwrap()));
+        assert!(addr.is_tls());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn parses_unix_addresses() {
+        let expected = ListenAddr::Unix(PathBuf::from("/run/winterjs.sock"));
+        assert_eq!(
+            "unix:/run/winterjs.sock".parse::<ListenAddr>().unwrap(),
+            expected
+        );
+        assert_eq!(
+            "unix:///run/winterjs.sock".parse::<ListenAddr>().unwrap(),
+            expected
+        );
+        assert!("unix:".parse::<ListenAddr>().is_err());
+        assert!("unix://".parse::<ListenAddr>().is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        for addr in [
+            "",
+            "localhost:8080",
+            "tcp://localhost:8080",
+            "tcp://127.0.0.1",
+            "tls://",
+            "http://127.0.0.1:8080",
+        ] {
+            assert!(addr.parse::<ListenAddr>().is_err(), "{addr}");
+        }
+    }
+
+    #[test]
+    fn displays_in_parseable_form() {
+        for addr in ["tcp://127.0.0.1:8080", "tls://[::]:443"] {
+            let parsed = addr.parse::<ListenAddr>().unwrap();
+            assert_eq!(parsed.to_string(), addr);
+            assert_eq!(parsed.to_string().parse::<ListenAddr>().unwrap(), parsed);
+        }
+    }
+
+    #[test]
+    fn parses_unix_socket_modes() {
+        assert_eq!(parse_unix_socket_mode("660"), Ok(0o660));
+        assert_eq!(parse_unix_socket_mode("0o777"), Ok(0o777));
+        assert!(parse_unix_socket_mode("1000").is_err());
+        assert!(parse_unix_socket_mode("689").is_err());
+    }
+}
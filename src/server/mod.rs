@@ -0,0 +1,835 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use async_trait::async_trait;
+use http::uri::{Authority, PathAndQuery, Scheme};
+use http::Uri;
+use hyper::server::conn::Http;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, watch, Semaphore};
+
+use self::access_log::{AccessLog, LoggedBody};
+use self::limits::{ConnectionActivity, Protocol, TimeoutStream};
+use self::listener::{Listener, Stream};
+use self::proxy_protocol::Rewind;
+
+mod access_log;
+mod forwarded;
+mod limits;
+mod listener;
+mod proxy_protocol;
+mod tls;
+
+pub use access_log::{AccessLogConfig, AccessLogFormat};
+pub use forwarded::{IpCidr, TrustedProxies};
+pub use limits::LimitsConfig;
+#[cfg(unix)]
+pub use listener::SystemdListenFds;
+pub use listener::{parse_unix_socket_mode, ListenAddr};
+pub use proxy_protocol::ProxyProtocolMode;
+pub use tls::TlsConfig;
+
+/// How long a client gets to complete the TLS handshake before the
+/// connection is dropped.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub listen: Vec<ListenAddr>,
+    pub tls: Option<TlsConfig>,
+    /// File mode to apply to Unix domain sockets after creating them.
+    pub unix_socket_mode: Option<u32>,
+    pub http2: Http2Config,
+    pub limits: LimitsConfig,
+    /// Whether to expect a PROXY protocol header on incoming connections.
+    pub proxy_protocol: Option<ProxyProtocolMode>,
+    /// Proxies whose `Forwarded` and `X-Forwarded-For` headers are used to
+    /// find the client address, and whose `X-Forwarded-Proto`,
+    /// `X-Forwarded-Host` and `Forwarded` headers are used to reconstruct
+    /// the request URL.
+    pub trusted_proxies: TrustedProxies,
+    /// The host to use in request URLs when the client didn't send one.
+    pub default_host: Authority,
+    pub access_log: Option<AccessLogConfig>,
+    /// Where to serve Prometheus metrics on `/metrics`.
+    pub metrics_listen: Option<SocketAddr>,
+    /// Where to accept administrative calls, such as `POST /reload`.
+    pub admin_listen: Option<SocketAddr>,
+    /// Paths of the liveness and readiness endpoints, which are answered
+    /// without going through the JavaScript code.
+    pub health_path: String,
+    pub ready_path: String,
+    /// How long to keep accepting connections after shutdown starts, with
+    /// the readiness endpoint already failing, so load balancers can stop
+    /// sending traffic before the listeners close.
+    pub drain_grace_period: Duration,
+    /// Sockets passed in through systemd's socket activation, which are
+    /// served instead of binding `listen`.
+    #[cfg(unix)]
+    pub systemd_listen_fds: Option<SystemdListenFds>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Http2Config {
+    /// Whether to accept HTTP/2 connections, either negotiated through ALPN
+    /// over TLS or as cleartext h2c with prior knowledge.
+    pub enabled: bool,
+    pub max_concurrent_streams: Option<u32>,
+    pub initial_stream_window_size: Option<u32>,
+    pub initial_connection_window_size: Option<u32>,
+}
+
+/// State shared by all listeners and connections.
+struct ServerState {
+    protocols: Protocols,
+    context: AppContext,
+    proxy_protocol: Option<ProxyProtocolMode>,
+    header_read_timeout: Option<Duration>,
+    keep_alive_timeout: Option<Duration>,
+    connection_limit: Option<Arc<Semaphore>>,
+}
+
+/// Connection settings for the protocols we speak.
+struct Protocols {
+    /// Used for plain connections and TLS connections that did not
+    /// negotiate h2. Detects the h2c connection preface when HTTP/2
+    /// is enabled.
+    default: Http,
+    /// Used for TLS connections that negotiated h2 through ALPN.
+    h2_only: Http,
+}
+
+impl Protocols {
+    fn new(config: &Http2Config, limits: &LimitsConfig) -> Self {
+        let mut default = Http::new();
+        // Oversized heads are answered with a 431 by hyper for HTTP/1, and
+        // by h2 for HTTP/2.
+        default
+            .max_buf_size(limits.max_header_size as usize)
+            .http2_max_header_list_size(limits.max_header_size);
+        if config.enabled {
+            default
+                .http2_max_concurrent_streams(config.max_concurrent_streams)
+                .http2_initial_stream_window_size(config.initial_stream_window_size)
+                .http2_initial_connection_window_size(config.initial_connection_window_size);
+        } else {
+            default.http1_only(true);
+        }
+
+        let mut h2_only = default.clone();
+        h2_only.http2_only(config.enabled);
+
+        Self { default, h2_only }
+    }
+}
+
+pub async fn run_server(
+    config: ServerConfig,
+    handler: BoxedDynRunner,
+    shutdown_signal: tokio::sync::oneshot::Receiver<()>,
+) -> Result<(), anyhow::Error> {
+    let access_log = match config.access_log {
+        Some(log_config) => Some(Arc::new(AccessLog::open(log_config)?)),
+        None => None,
+    };
+    #[cfg(unix)]
+    if let Some(log) = &access_log {
+        tokio::spawn(access_log::reopen_on_sighup(log.clone()));
+    }
+
+    let (draining_tx, draining_rx) = watch::channel(false);
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let state = Arc::new(ServerState {
+        protocols: Protocols::new(&config.http2, &config.limits),
+        context: AppContext {
+            runner: handler,
+            trusted_proxies: Arc::new(config.trusted_proxies),
+            default_host: config.default_host,
+            max_body_size: config.limits.max_body_size,
+            access_log,
+            health_path: config.health_path.into(),
+            ready_path: config.ready_path.into(),
+            draining: draining_rx,
+        },
+        proxy_protocol: config.proxy_protocol,
+        header_read_timeout: config.limits.header_read_timeout,
+        keep_alive_timeout: config.limits.keep_alive_timeout,
+        connection_limit: config
+            .limits
+            .max_connections
+            .map(|max| Arc::new(Semaphore::new(max))),
+    });
+
+    #[cfg(unix)]
+    let inherited = config
+        .systemd_listen_fds
+        .as_ref()
+        .map(SystemdListenFds::adopt)
+        .transpose()?;
+    #[cfg(not(unix))]
+    let inherited = None;
+
+    let listeners = match inherited {
+        Some(listeners) => listeners,
+        None => {
+            let mut listeners = vec![];
+            for addr in &config.listen {
+                let listener = Listener::bind(addr, config.unix_socket_mode).await?;
+                listeners.push((addr.clone(), listener));
+            }
+            listeners
+        }
+    };
+
+    let needs_tls = listeners.iter().any(|(addr, _)| addr.is_tls());
+    let tls_acceptor = match config.tls {
+        Some(ref tls_config) if needs_tls => {
+            let (acceptor, resolver) = tls::build_acceptor(tls_config, config.http2.enabled)
+                .context("Failed to set up TLS")?;
+            tokio::spawn(resolver.watch_for_changes());
+            Some(acceptor)
+        }
+        Some(_) => {
+            tracing::warn!("A TLS certificate was provided, but none of the listeners use TLS");
+            None
+        }
+        None => None,
+    };
+
+    if let Some(addr) = config.metrics_listen {
+        serve_internal(addr, "metrics", shutdown_rx.clone(), metrics_response)?;
+    }
+
+    if let Some(addr) = config.admin_listen {
+        let runner = state.context.runner.clone();
+        serve_internal(addr, "admin calls", shutdown_rx.clone(), move |req| {
+            admin_response(runner.clone(), req)
+        })?;
+    }
+
+    tokio::spawn({
+        let runner = state.context.runner.clone();
+        async move { runner.supervise().await }
+    });
+
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sigusr2(state.context.runner.clone()));
+
+    // Every connection task holds a clone of the sender, so the receiver
+    // only returns once all of them are done.
+    let (connection_tracker, mut connections_finished) = mpsc::channel::<()>(1);
+
+    for (addr, listener) in listeners {
+        let tls = if addr.is_tls() {
+            Some(tls_acceptor.clone().ok_or_else(|| {
+                anyhow::anyhow!("Listening on '{addr}' requires a TLS certificate and key")
+            })?)
+        } else {
+            None
+        };
+
+        tracing::info!(listen=%addr, "starting server on '{addr}'");
+
+        tokio::spawn(accept_connections(
+            listener,
+            tls,
+            state.clone(),
+            shutdown_rx.clone(),
+            connection_tracker.clone(),
+        ));
+    }
+    drop(connection_tracker);
+
+    _ = shutdown_signal.await;
+
+    // Fail the readiness probe first, while still accepting connections, so
+    // load balancers notice before we stop answering altogether.
+    draining_tx.send_replace(true);
+    if !config.drain_grace_period.is_zero() {
+        tracing::info!(
+            "Waiting {:?} for load balancers to stop sending requests",
+            config.drain_grace_period
+        );
+        tokio::time::sleep(config.drain_grace_period).await;
+    }
+
+    // Stop accepting new connections and ask the existing ones to finish
+    // their in-flight requests.
+    shutdown_tx.send_replace(true);
+    _ = connections_finished.recv().await;
+
+    Ok(())
+}
+
+async fn accept_connections(
+    listener: Listener,
+    tls: Option<tokio_rustls::TlsAcceptor>,
+    state: Arc<ServerState>,
+    mut shutdown: watch::Receiver<bool>,
+    connection_tracker: mpsc::Sender<()>,
+) {
+    loop {
+        // Leave connections in the listen backlog while we're at capacity
+        let permit = match &state.connection_limit {
+            Some(limit) => tokio::select! {
+                permit = limit.clone().acquire_owned() => {
+                    Some(permit.expect("The connection limit is never closed"))
+                }
+                _ = shutdown_requested(&mut shutdown) => break,
+            },
+            None => None,
+        };
+
+        let (stream, remote_addr) = tokio::select! {
+            res = listener.accept() => match res {
+                Ok(s) => s,
+                Err(e) if is_connection_error(&e) => continue,
+                Err(e) => {
+                    // Most likely out of file descriptors; back off for a bit
+                    // instead of spinning.
+                    tracing::error!(error = %e, "Failed to accept connection");
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = shutdown_requested(&mut shutdown) => break,
+        };
+
+        let tls = tls.clone();
+        let state = state.clone();
+        let shutdown = shutdown.clone();
+        let connection_tracker = connection_tracker.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, remote_addr, tls, &state, shutdown).await {
+                tracing::debug!(%remote_addr, error = %e, "Error while serving connection");
+            }
+
+            drop(permit);
+            drop(connection_tracker);
+        });
+    }
+}
+
+async fn handle_connection(
+    stream: Stream,
+    mut remote_addr: SocketAddr,
+    tls: Option<tokio_rustls::TlsAcceptor>,
+    state: &ServerState,
+    shutdown: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let accepted_at = tokio::time::Instant::now();
+
+    // The PROXY header comes before everything else, including the TLS
+    // handshake. Both count towards the time allowed for the request head.
+    let stream = match state.proxy_protocol {
+        Some(mode) if mode.expects_header(remote_addr.ip(), &state.context.trusted_proxies) => {
+            let (addr, stream) = proxy_protocol::read_header(stream)
+                .await
+                .context("Failed to read PROXY protocol header")?;
+            if let Some(addr) = addr {
+                remote_addr = addr;
+            }
+            stream
+        }
+        _ => Rewind::new(stream, vec![]),
+    };
+
+    let mut connection = ConnectionInfo {
+        remote_addr,
+        tls: false,
+    };
+    match tls {
+        None => {
+            serve_connection(
+                &state.protocols.default,
+                Protocol::Unknown,
+                stream,
+                connection,
+                accepted_at,
+                state,
+                shutdown,
+            )
+            .await?
+        }
+        Some(tls) => {
+            let stream = accept_tls(&tls, stream)
+                .await
+                .context("TLS handshake failed")?;
+            let (http, protocol) = if stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+                (&state.protocols.h2_only, Protocol::Http2)
+            } else {
+                (&state.protocols.default, Protocol::Unknown)
+            };
+            connection.tls = true;
+            serve_connection(
+                http,
+                protocol,
+                stream,
+                connection,
+                accepted_at,
+                state,
+                shutdown,
+            )
+            .await?
+        }
+    }
+
+    Ok(())
+}
+
+async fn accept_tls<I: AsyncRead + AsyncWrite + Unpin>(
+    tls: &tokio_rustls::TlsAcceptor,
+    stream: I,
+) -> std::io::Result<tokio_rustls::server::TlsStream<I>> {
+    tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(stream))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "TLS handshake timed out"))?
+}
+
+async fn serve_connection<I>(
+    http: &Http,
+    protocol: Protocol,
+    io: I,
+    connection: ConnectionInfo,
+    accepted_at: tokio::time::Instant,
+    state: &ServerState,
+    mut shutdown: watch::Receiver<bool>,
+) -> hyper::Result<()>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let activity = Arc::new(ConnectionActivity::new());
+    let io = TimeoutStream::new(
+        io,
+        activity.clone(),
+        state.header_read_timeout,
+        protocol,
+        accepted_at,
+    );
+
+    // Create a `Service` for responding to the request.
+    let context = state.context.clone();
+    let service = service_fn({
+        let activity = activity.clone();
+        let draining = shutdown.clone();
+        move |req: Request<Body>| {
+            let request = activity.start_request();
+            let http1 = req.version() < http::Version::HTTP_2;
+            let draining = draining.clone();
+            let res = handle(context.clone(), connection, req);
+            async move {
+                let mut res = res.await;
+                drop(request);
+                // Let HTTP/1 clients know they can't reuse the connection
+                // while we're shutting down. HTTP/2 clients get a GOAWAY.
+                if http1 && *draining.borrow() {
+                    if let Ok(res) = &mut res {
+                        res.headers_mut().insert(
+                            http::header::CONNECTION,
+                            http::HeaderValue::from_static("close"),
+                        );
+                    }
+                }
+                res
+            }
+        }
+    });
+
+    let connection = http.serve_connection(io, service).with_upgrades();
+    tokio::pin!(connection);
+
+    let idle = async {
+        match state.keep_alive_timeout {
+            Some(timeout) => activity.idle_for(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    tokio::select! {
+        res = connection.as_mut() => return res,
+        _ = shutdown_requested(&mut shutdown) => (),
+        _ = idle => tracing::debug!("Closing idle connection"),
+    }
+
+    // hyper doesn't close HTTP/1 connections that haven't sent a request
+    // yet when shutting down, and there's nothing to be graceful about when
+    // no request is in flight anyway. HTTP/2 connections get a GOAWAY.
+    if !activity.is_busy() && !activity.is_http2() {
+        return Ok(());
+    }
+
+    connection.as_mut().graceful_shutdown();
+    connection.await
+}
+
+async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+    // The guard returned by `wait_for` isn't `Send`, so it must not be held
+    // across an await point in the connection tasks.
+    _ = shutdown.wait_for(|s| *s).await;
+}
+
+fn is_connection_error(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::ConnectionRefused
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::ConnectionReset
+    )
+}
+
+#[async_trait]
+#[dyn_clonable::clonable]
+pub trait Runner: Send + Sync + Clone + 'static {
+    async fn handle(
+        &self,
+        addr: SocketAddr,
+        req: http::request::Parts,
+        body: hyper::Body,
+    ) -> anyhow::Result<hyper::Response<hyper::Body>>;
+
+    /// Reports on the health of the workers, for the health and readiness
+    /// endpoints.
+    async fn status(&self) -> RunnerStatus;
+
+    /// Looks after the workers, replacing the ones that failed and stopping
+    /// the ones no longer needed, until the runner shuts down.
+    async fn supervise(&self) {}
+
+    /// Loads the user code again and switches over to it once it has been
+    /// initialized. Requests already in flight finish on the previous
+    /// version, which keeps serving if the new code fails to initialize.
+    async fn reload(&self) -> anyhow::Result<()>;
+
+    async fn shutdown(&self, timeout: Option<Duration>) -> ShutdownOutcome;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShutdownOutcome {
+    /// All in-flight requests were handled before the workers quit.
+    Completed,
+    /// The shutdown timeout was reached, and the workers were terminated
+    /// with requests still in flight.
+    TimedOut,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RunnerStatus {
+    /// At least one worker has evaluated the user code and is taking
+    /// requests, and shutdown hasn't started.
+    pub ready: bool,
+    /// At least one worker thread is still running.
+    pub alive: bool,
+}
+
+pub type BoxedDynRunner = Box<dyn Runner>;
+
+/// Runners attach this to the extensions of their responses to report which
+/// worker thread handled the request, for the access log.
+#[derive(Clone, Copy, Debug)]
+pub struct WorkerIndex(pub usize);
+
+/// Requests carry their ID in this header. IDs set by trusted proxies are
+/// kept, so a request can be followed across services.
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+#[derive(Clone)]
+struct AppContext {
+    runner: BoxedDynRunner,
+    trusted_proxies: Arc<TrustedProxies>,
+    default_host: Authority,
+    max_body_size: Option<u64>,
+    access_log: Option<Arc<AccessLog>>,
+    health_path: Arc<str>,
+    ready_path: Arc<str>,
+    /// Set once shutdown starts, which is a while before the listeners
+    /// close if there's a drain grace period.
+    draining: watch::Receiver<bool>,
+}
+
+/// What we know about the connection a request came in on.
+#[derive(Clone, Copy, Debug)]
+struct ConnectionInfo {
+    /// The peer address, or the one from the PROXY header if enabled.
+    remote_addr: SocketAddr,
+    /// Whether the connection was made to a TLS listener.
+    tls: bool,
+}
+
+async fn handle(
+    context: AppContext,
+    connection: ConnectionInfo,
+    mut req: Request<Body>,
+) -> Result<Response<LoggedBody>, Infallible> {
+    // Probes come in all the time, so they're kept out of the logs and
+    // metrics
+    if let Some(res) = probe_response(&context, req.uri().path()).await {
+        return Ok(LoggedBody::wrap(res, None));
+    }
+
+    let started = std::time::Instant::now();
+    let client_addr = context
+        .trusted_proxies
+        .client_addr(connection.remote_addr, req.headers());
+    let request_id = assign_request_id(&context, connection, req.headers_mut());
+    let log = context.access_log.clone().map(|log| {
+        (
+            log,
+            access_log::Entry::new(&req, client_addr.ip(), request_id),
+        )
+    });
+
+    let res = match handle_inner(context, connection, client_addr, req).await {
+        Ok(r) => r,
+        Err(err) => {
+            tracing::error!(error = format!("{err:#?}"), "could not process request");
+
+            hyper::Response::builder()
+                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+                .body(hyper::Body::from(err.to_string()))
+                .unwrap()
+        }
+    };
+
+    crate::metrics::METRICS.record_request(res.status(), started.elapsed());
+
+    Ok(LoggedBody::wrap(res, log))
+}
+
+async fn probe_response(context: &AppContext, path: &str) -> Option<Response<Body>> {
+    let (ok, message) = if path == &*context.health_path {
+        if context.runner.status().await.alive {
+            (true, "OK")
+        } else {
+            (false, "All worker threads have died")
+        }
+    } else if path == &*context.ready_path {
+        let shutting_down = *context.draining.borrow();
+        if shutting_down {
+            (false, "Shutting down")
+        } else if context.runner.status().await.ready {
+            (true, "Ready")
+        } else {
+            (false, "Not ready")
+        }
+    } else {
+        return None;
+    };
+
+    let status = if ok {
+        hyper::StatusCode::OK
+    } else {
+        hyper::StatusCode::SERVICE_UNAVAILABLE
+    };
+    Some(
+        Response::builder()
+            .status(status)
+            .header(http::header::CACHE_CONTROL, "no-store")
+            .body(Body::from(message))
+            .unwrap(),
+    )
+}
+
+/// Serves internal endpoints, which aren't part of the app, on their own
+/// address.
+fn serve_internal<F, R>(
+    addr: SocketAddr,
+    name: &'static str,
+    mut shutdown: watch::Receiver<bool>,
+    handle: F,
+) -> anyhow::Result<()>
+where
+    F: Fn(Request<Body>) -> R + Clone + Send + Sync + 'static,
+    R: Future<Output = Result<Response<Body>, Infallible>> + Send + 'static,
+{
+    let server = hyper::Server::try_bind(&addr)
+        .with_context(|| format!("Failed to listen for {name} on '{addr}'"))?
+        .serve(make_service_fn(move |_| {
+            let handle = handle.clone();
+            async move { Ok::<_, Infallible>(service_fn(handle)) }
+        }));
+
+    tracing::info!(listen=%addr, "serving {name} on '{addr}'");
+
+    tokio::spawn(async move {
+        let server =
+            server.with_graceful_shutdown(async move { shutdown_requested(&mut shutdown).await });
+        if let Err(e) = server.await {
+            tracing::error!(error = %e, "Server for {name} failed");
+        }
+    });
+
+    Ok(())
+}
+
+async fn metrics_response(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if req.uri().path() != "/metrics" {
+        return Ok(limits::error_response(hyper::StatusCode::NOT_FOUND));
+    }
+
+    Ok(Response::builder()
+        .header(http::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(crate::metrics::METRICS.render()))
+        .unwrap())
+}
+
+async fn admin_response(
+    runner: BoxedDynRunner,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    if req.uri().path() != "/reload" {
+        return Ok(limits::error_response(hyper::StatusCode::NOT_FOUND));
+    }
+    if req.method() != http::Method::POST {
+        return Ok(limits::error_response(
+            hyper::StatusCode::METHOD_NOT_ALLOWED,
+        ));
+    }
+
+    Ok(match reload(&runner).await {
+        Ok(()) => Response::new(Body::from("Reloaded")),
+        Err(e) => Response::builder()
+            .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!("{e:#}")))
+            .unwrap(),
+    })
+}
+
+/// Reloads the code whenever SIGUSR2 is received. SIGHUP is taken by the
+/// access log, which is reopened on it after rotation.
+#[cfg(unix)]
+async fn reload_on_sigusr2(runner: BoxedDynRunner) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut usr2 = match signal(SignalKind::user_defined2()) {
+        Ok(s) => s,
+        Err(e) => {
+            tracing::warn!(error = %e, "Failed to listen for SIGUSR2, code won't be reloaded");
+            return;
+        }
+    };
+
+    while usr2.recv().await.is_some() {
+        _ = reload(&runner).await;
+    }
+}
+
+async fn reload(runner: &BoxedDynRunner) -> anyhow::Result<()> {
+    let res = runner.reload().await;
+    if let Err(e) = &res {
+        tracing::error!(
+            error = format!("{e:#}"),
+            "Failed to reload code, the previous version is still being served"
+        );
+    }
+    res
+}
+
+fn assign_request_id(
+    context: &AppContext,
+    connection: ConnectionInfo,
+    headers: &mut http::HeaderMap,
+) -> String {
+    if context
+        .trusted_proxies
+        .is_trusted(connection.remote_addr.ip())
+    {
+        if let Some(id) = headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|id| id.to_str().ok())
+        {
+            return id.to_string();
+        }
+    }
+
+    let id = uuid::Uuid::new_v4().simple().to_string();
+    headers.insert(
+        REQUEST_ID_HEADER,
+        http::HeaderValue::from_str(&id).expect("UUIDs are valid header values"),
+    );
+    id
+}
+
+async fn handle_inner(
+    context: AppContext,
+    connection: ConnectionInfo,
+    client_addr: SocketAddr,
+    req: Request<Body>,
+) -> Result<Response<Body>, anyhow::Error> {
+    let (mut parts, body) = req.into_parts();
+
+    parts.uri = match effective_request_uri(&context, connection, &parts) {
+        Ok(uri) => uri,
+        Err(e) => {
+            return Ok(hyper::Response::builder()
+                .status(hyper::StatusCode::BAD_REQUEST)
+                .body(hyper::Body::from(format!("{e:#}")))
+                .unwrap())
+        }
+    };
+
+    let (body, body_limit) = match limits::limit_body(body, context.max_body_size) {
+        Ok(body) => body,
+        Err(status) => return Ok(limits::error_response(status)),
+    };
+
+    let res = context
+        .runner
+        .handle(client_addr, parts, body)
+        .await
+        .context("JavaScript failed");
+    body_limit.check(res)
+}
+
+/// Reconstructs the URI the client used to make the request, as described
+/// in RFC 9110, section 7.1. The scheme and host forwarded by trusted
+/// proxies take precedence over what we see on the connection itself.
+fn effective_request_uri(
+    context: &AppContext,
+    connection: ConnectionInfo,
+    parts: &http::request::Parts,
+) -> anyhow::Result<Uri> {
+    let forwarded = context
+        .trusted_proxies
+        .forwarded_origin(connection.remote_addr, &parts.headers);
+
+    let scheme = match forwarded.scheme {
+        Some(scheme) => scheme,
+        None if connection.tls => Scheme::HTTPS,
+        None => Scheme::HTTP,
+    };
+
+    let authority = match forwarded.host {
+        Some(host) => host,
+        None => match parts.uri.authority() {
+            // Absolute-form requests and HTTP/2 carry the authority in the URI
+            Some(authority) => authority.clone(),
+            None => match parts.headers.get(http::header::HOST) {
+                Some(host) if !host.is_empty() => {
+                    Authority::try_from(host.as_bytes()).context("Invalid host header")?
+                }
+                _ => context.default_host.clone(),
+            },
+        },
+    };
+
+    Uri::builder()
+        .scheme(scheme)
+        .authority(authority)
+        .path_and_query(
+            parts
+                .uri
+                .path_and_query()
+                .cloned()
+                .unwrap_or_else(|| PathAndQuery::from_static("/")),
+        )
+        .build()
+        .context("Failed to build request URI")
+}
@@ -0,0 +1,427 @@
+//! Support for HAProxy's PROXY protocol, versions 1 and 2. See
+//! https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt for the spec.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+use super::forwarded::TrustedProxies;
+
+/// How long a client gets to send the PROXY header.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// The longest possible v1 header, including the trailing CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LENGTH: usize = 16;
+
+/// Which connections start with a PROXY header. The spec doesn't allow
+/// guessing whether a header was sent, since anyone could send one, so
+/// that's always decided by the peer's address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProxyProtocolMode {
+    /// Connections from trusted proxies must start with a PROXY header, and
+    /// connections from anyone else are served as they are.
+    Optional,
+    /// Every connection must start with a PROXY header.
+    Required,
+}
+
+impl ProxyProtocolMode {
+    /// Returns true if a connection from `peer` must start with a PROXY
+    /// header.
+    pub fn expects_header(self, peer: IpAddr, trusted_proxies: &TrustedProxies) -> bool {
+        match self {
+            Self::Optional => trusted_proxies.is_trusted(peer),
+            Self::Required => true,
+        }
+    }
+}
+
+/// Reads the PROXY header from the start of the stream, returning the
+/// client address it carries. Streams that don't start with a valid header
+/// are rejected. The address is `None` if the header doesn't carry an IP
+/// address, such as for health checks done by the proxy itself.
+pub async fn read_header<S: AsyncRead + Unpin>(
+    stream: S,
+) -> io::Result<(Option<SocketAddr>, Rewind<S>)> {
+    tokio::time::timeout(HEADER_TIMEOUT, read_header_inner(stream))
+        .await
+        .map_err(|_| invalid_data("Timed out while reading PROXY header"))?
+}
+
+async fn read_header_inner<S: AsyncRead + Unpin>(
+    mut stream: S,
+) -> io::Result<(Option<SocketAddr>, Rewind<S>)> {
+    let mut buf = Vec::with_capacity(V1_MAX_LENGTH);
+
+    loop {
+        let header = if starts_with_prefix_of(&buf, V2_SIGNATURE) {
+            parse_v2(&buf)?
+        } else if starts_with_prefix_of(&buf, V1_PREFIX) {
+            parse_v1(&buf)?
+        } else {
+            return Err(invalid_data("Connection did not start with a PROXY header"));
+        };
+
+        if let Some((addr, header_length)) = header {
+            // Anything we read past the header belongs to the actual
+            // request, and must be handed to the HTTP server as-is.
+            buf.drain(..header_length);
+            return Ok((addr, Rewind::new(stream, buf)));
+        }
+
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+}
+
+/// Returns true if `buf` and `prefix` agree on their common length, meaning
+/// `buf` may still turn out to start with `prefix`.
+fn starts_with_prefix_of(buf: &[u8], prefix: &[u8]) -> bool {
+    let len = buf.len().min(prefix.len());
+    buf[..len] == prefix[..len]
+}
+
+/// Returns the address and the length of the header, or `None` if more
+/// data is needed.
+fn parse_v1(buf: &[u8]) -> io::Result<Option<(Option<SocketAddr>, usize)>> {
+    // The CRLF has to be within the longest possible header, no matter how
+    // much was read already
+    let line = &buf[..buf.len().min(V1_MAX_LENGTH)];
+    let Some(end) = line.windows(2).position(|w| w == b"\r\n") else {
+        if buf.len() >= V1_MAX_LENGTH {
+            return Err(invalid_data("PROXY v1 header is too long"));
+        }
+        return Ok(None);
+    };
+
+    let line = std::str::from_utf8(&buf[..end])
+        .map_err(|_| invalid_data("PROXY v1 header is not valid ASCII"))?;
+    let mut parts = line.split(' ').skip(1);
+
+    let addr = match parts.next() {
+        Some("UNKNOWN") => None,
+        Some(protocol @ ("TCP4" | "TCP6")) => {
+            let (Some(src_ip), Some(_dst_ip), Some(src_port), Some(_dst_port), None) = (
+                parts.next(),
+                parts.next(),
+                parts.next(),
+                parts.next(),
+                parts.next(),
+            ) else {
+                return Err(invalid_data("Malformed PROXY v1 header"));
+            };
+
+            let ip: IpAddr = match protocol {
+                "TCP4" => src_ip.parse::<Ipv4Addr>().map(Into::into),
+                _ => src_ip.parse::<Ipv6Addr>().map(Into::into),
+            }
+            .map_err(|_| invalid_data("Invalid source address in PROXY v1 header"))?;
+            let port: u16 = src_port
+                .parse()
+                .map_err(|_| invalid_data("Invalid source port in PROXY v1 header"))?;
+
+            Some(SocketAddr::new(ip, port))
+        }
+        _ => return Err(invalid_data("Unknown protocol in PROXY v1 header")),
+    };
+
+    Ok(Some((addr, end + 2)))
+}
+
+/// Returns the address and the length of the header, or `None` if more
+/// data is needed.
+fn parse_v2(buf: &[u8]) -> io::Result<Option<(Option<SocketAddr>, usize)>> {
+    if buf.len() < V2_HEADER_LENGTH {
+        return Ok(None);
+    }
+
+    let version_command = buf[12];
+    let family = buf[13];
+    let length = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid_data("Unsupported PROXY protocol version"));
+    }
+
+    let total_length = V2_HEADER_LENGTH + length;
+    if buf.len() < total_length {
+        return Ok(None);
+    }
+    let addresses = &buf[V2_HEADER_LENGTH..total_length];
+
+    let addr = match version_command & 0x0f {
+        // LOCAL: the connection was made by the proxy itself, so we keep
+        // the real peer address
+        0x0 => None,
+
+        // PROXY
+        0x1 => match family >> 4 {
+            // AF_INET
+            0x1 => {
+                if addresses.len() < 12 {
+                    return Err(invalid_data("Truncated addresses in PROXY v2 header"));
+                }
+                let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+                let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+                Some(SocketAddr::new(ip.into(), port))
+            }
+
+            // AF_INET6
+            0x2 => {
+                if addresses.len() < 36 {
+                    return Err(invalid_data("Truncated addresses in PROXY v2 header"));
+                }
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&addresses[..16]);
+                let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+                Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port))
+            }
+
+            // AF_UNSPEC and AF_UNIX don't carry an IP address
+            0x0 | 0x3 => None,
+
+            _ => return Err(invalid_data("Unknown address family in PROXY v2 header")),
+        },
+
+        _ => return Err(invalid_data("Unknown command in PROXY v2 header")),
+    };
+
+    Ok(Some((addr, total_length)))
+}
+
+fn invalid_data(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A stream that replays data that was already read from the underlying
+/// stream before reading any further.
+pub struct Rewind<S> {
+    prefix: Vec<u8>,
+    position: usize,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    pub fn new(inner: S, prefix: Vec<u8>) -> Self {
+        Self {
+            prefix,
+            position: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.position < this.prefix.len() {
+            let remaining = &this.prefix[this.position..];
+            let len = remaining.len().min(buf.remaining());
+            buf.put_slice(&remaining[..len]);
+            this.position += len;
+
+            if this.position == this.prefix.len() {
+                // Free up the memory, we won't need it again
+                this.prefix = vec![];
+                this.position = 0;
+            }
+
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2_header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[test]
+    fn v1_tcp4() {
+        let buf = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /";
+        let (addr, length) = parse_v1(buf).unwrap().unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(&buf[length..], b"GET /");
+    }
+
+    #[test]
+    fn v1_tcp6() {
+        let buf = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n";
+        let (addr, length) = parse_v1(buf).unwrap().unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+        assert_eq!(length, buf.len());
+    }
+
+    #[test]
+    fn v1_unknown() {
+        let buf = b"PROXY UNKNOWN ffff:f...f:ffff ffff:f...f:ffff 65535 65535\r\n";
+        assert_eq!(parse_v1(buf).unwrap(), Some((None, buf.len())));
+    }
+
+    #[test]
+    fn v1_truncated() {
+        assert_eq!(parse_v1(b"PROXY TCP4 192.0.2.1 198.51").unwrap(), None);
+        assert_eq!(
+            parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 1 2\r").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn v1_too_long() {
+        let mut line = b"PROXY UNKNOWN ".to_vec();
+        line.resize(V1_MAX_LENGTH, b'a');
+        assert!(parse_v1(&line).is_err());
+
+        // Even when the CRLF arrived in the same read
+        line.extend_from_slice(b"\r\n");
+        assert!(parse_v1(&line).is_err());
+
+        let mut longest = b"PROXY UNKNOWN ".to_vec();
+        longest.resize(V1_MAX_LENGTH - 2, b'a');
+        longest.extend_from_slice(b"\r\n");
+        assert_eq!(parse_v1(&longest).unwrap(), Some((None, V1_MAX_LENGTH)));
+    }
+
+    #[test]
+    fn v1_malformed() {
+        assert!(parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP4 2001:db8::1 2001:db8::2 56324 443\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 443\r\n").is_err());
+        assert!(parse_v1(b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n").is_err());
+    }
+
+    #[test]
+    fn v2_inet() {
+        let mut buf = v2_header(
+            0x1,
+            0x11,
+            &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 1, 187],
+        );
+        let header_length = buf.len();
+        buf.extend_from_slice(b"GET /");
+        let (addr, length) = parse_v2(&buf).unwrap().unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(length, header_length);
+    }
+
+    #[test]
+    fn v2_inet6() {
+        let mut addresses = Vec::new();
+        addresses.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        addresses.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        addresses.extend_from_slice(&[0xdc, 0x04, 1, 187]);
+        let buf = v2_header(0x1, 0x21, &addresses);
+        let (addr, _) = parse_v2(&buf).unwrap().unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn v2_local() {
+        let buf = v2_header(0x0, 0x00, &[]);
+        assert_eq!(parse_v2(&buf).unwrap(), Some((None, buf.len())));
+
+        // Addresses sent along with LOCAL are ignored
+        let buf = v2_header(0x0, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0, 1, 0, 2]);
+        assert_eq!(parse_v2(&buf).unwrap(), Some((None, buf.len())));
+    }
+
+    #[test]
+    fn v2_truncated() {
+        let buf = v2_header(
+            0x1,
+            0x11,
+            &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 1, 187],
+        );
+        for length in 0..buf.len() {
+            assert_eq!(parse_v2(&buf[..length]).unwrap(), None);
+        }
+
+        // The length covers fewer bytes than the address family needs
+        let buf = v2_header(0x1, 0x11, &[192, 0, 2, 1]);
+        assert!(parse_v2(&buf).is_err());
+    }
+
+    #[test]
+    fn v2_invalid() {
+        let mut buf = v2_header(0x1, 0x11, &[0; 12]);
+        buf[12] = 0x11;
+        assert!(parse_v2(&buf).is_err());
+
+        assert!(parse_v2(&v2_header(0x2, 0x11, &[0; 12])).is_err());
+        assert!(parse_v2(&v2_header(0x1, 0x41, &[0; 12])).is_err());
+    }
+
+    #[tokio::test]
+    async fn replays_data_after_header() {
+        let stream: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        let (addr, mut stream) = read_header(stream).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_header() {
+        let stream: &[u8] = b"GET / HTTP/1.1\r\n";
+        assert!(read_header(stream).await.is_err());
+    }
+}
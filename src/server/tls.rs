@@ -0,0 +1,173 @@
+//! TLS termination for the built-in server. Certificates are read from PEM
+//! files and periodically re-read from disk, so renewed certificates are
+//! picked up without restarting the process.
+
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Context as _};
+use parking_lot::RwLock;
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs1KeyDer, PrivatePkcs8KeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+
+/// How often the certificate and key files are checked for changes.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+pub fn build_acceptor(
+    config: &TlsConfig,
+    enable_http2: bool,
+) -> anyhow::Result<(tokio_rustls::TlsAcceptor, Arc<CertResolver>)> {
+    let resolver = Arc::new(CertResolver::new(
+        config.cert_path.clone(),
+        config.key_path.clone(),
+    )?);
+
+    let mut server_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+    server_config.alpn_protocols = if enable_http2 {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    };
+
+    Ok((
+        tokio_rustls::TlsAcceptor::from(Arc::new(server_config)),
+        resolver,
+    ))
+}
+
+/// Serves the most recently loaded certificate, and reloads it when
+/// the files on disk change.
+#[derive(Debug)]
+pub struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<LoadedCert>,
+}
+
+#[derive(Debug)]
+struct LoadedCert {
+    key: Arc<CertifiedKey>,
+    modified: (Option<SystemTime>, Option<SystemTime>),
+}
+
+impl CertResolver {
+    fn new(cert_path: PathBuf, key_path: PathBuf) -> anyhow::Result<Self> {
+        let current = load_cert(&cert_path, &key_path)?;
+        Ok(Self {
+            cert_path,
+            key_path,
+            current: RwLock::new(current),
+        })
+    }
+
+    /// Checks the certificate files periodically and swaps in the new
+    /// certificate when they change. Failing to load the new files is not
+    /// fatal; the previous certificate keeps being served.
+    pub async fn watch_for_changes(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(RELOAD_CHECK_INTERVAL).await;
+
+            let modified = (
+                modified_time(&self.cert_path),
+                modified_time(&self.key_path),
+            );
+            if modified == self.current.read().modified {
+                continue;
+            }
+
+            match load_cert(&self.cert_path, &self.key_path) {
+                Ok(cert) => {
+                    *self.current.write() = cert;
+                    tracing::info!(
+                        cert = %self.cert_path.display(),
+                        "Reloaded TLS certificate"
+                    );
+                }
+                Err(e) => {
+                    // Remember the timestamps anyway so a half-written file
+                    // doesn't produce a warning every few seconds; the next
+                    // write will trigger another attempt.
+                    self.current.write().modified = modified;
+                    tracing::warn!(
+                        error = format!("{e:#}"),
+                        "Failed to reload TLS certificate, continuing with the previous one"
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().key.clone())
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn load_cert(cert_path: &Path, key_path: &Path) -> anyhow::Result<LoadedCert> {
+    // Read the timestamps first, so a write racing with the read below is
+    // picked up on the next check.
+    let modified = (modified_time(cert_path), modified_time(key_path));
+
+    let certs = read_certs(cert_path)?;
+    let key = read_private_key(key_path)?;
+    let signing_key = rustls::crypto::ring::sign::any_supported_type(&key)
+        .map_err(|e| anyhow::anyhow!("Unsupported private key in '{}': {e}", key_path.display()))?;
+
+    Ok(LoadedCert {
+        key: Arc::new(CertifiedKey::new(certs, signing_key)),
+        modified,
+    })
+}
+
+fn read_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open TLS certificate file '{}'", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to parse TLS certificate file '{}'", path.display()))?;
+    if certs.is_empty() {
+        bail!("No certificates found in '{}'", path.display());
+    }
+    Ok(certs.into_iter().map(CertificateDer::from).collect())
+}
+
+fn read_private_key(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open TLS key file '{}'", path.display()))?;
+    let mut reader = BufReader::new(file);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .with_context(|| format!("Failed to parse TLS key file '{}'", path.display()))?
+        {
+            Some(rustls_pemfile::Item::PKCS8Key(key)) => {
+                return Ok(PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key)))
+            }
+            Some(rustls_pemfile::Item::RSAKey(key)) => {
+                return Ok(PrivateKeyDer::Pkcs1(PrivatePkcs1KeyDer::from(key)))
+            }
+            Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKeyDer::Sec1(key.into())),
+            Some(_) => (),
+            None => bail!("No private key found in '{}'", path.display()),
+        }
+    }
+}
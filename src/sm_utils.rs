@@ -1,13 +1,34 @@
-use std::{ffi::OsStr, path::Path};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    ffi::{c_void, OsStr, OsString},
+    future::Future,
+    path::Path,
+    sync::{Arc, Mutex},
+    task::{Poll, Waker},
+    time::Duration,
+};
 
-use anyhow::{anyhow, Context as _};
-use ion::{module::ModuleLoader, Context, ErrorReport};
+use anyhow::{anyhow, bail, Context as _};
+use ion::{
+    module::{ModuleData, ModuleLoader, ModuleRequest},
+    Context, ErrorReport, PermanentHeap,
+};
 use mozjs::{
-    jsapi::WeakRefSpecifier,
-    rust::{JSEngine, JSEngineHandle, RealmOptions},
+    jsapi::{
+        CompileGlobalScriptToStencil, CompileModuleScriptToStencil, InstantiateGlobalStencil,
+        InstantiateModuleStencil, InstantiateOptions, JSAutoRealm, JSContext, JSGCParamKey,
+        JSObject, JS_NewGlobalObject, JS_SetGCParameter, OnNewGlobalHookOption, SetModulePrivate,
+        SetOutOfMemoryCallback, Stencil, StencilRelease, WeakRefSpecifier,
+    },
+    rust::{
+        transform_str_to_source_text, CompileOptionsWrapper, JSEngine, JSEngineHandle,
+        RealmOptions, SIMPLE_GLOBAL_CLASS,
+    },
 };
 use runtime::{module::StandardModules, Runtime, RuntimeBuilder};
 use self_cell::self_cell;
+use sha2::{Digest, Sha256};
 
 pub static ENGINE: once_cell::sync::Lazy<JSEngineHandle> = once_cell::sync::Lazy::new(|| {
     let engine = JSEngine::init().expect("could not create engine");
@@ -30,6 +51,45 @@ macro_rules! ion_err {
     };
 }
 
+/// Limits and tuning for the garbage-collected heap of a runtime. SpiderMonkey's
+/// defaults are used for anything that's not set.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeapConfig {
+    /// Allocations beyond this fail, and the runtime is out of memory.
+    pub max_bytes: Option<u32>,
+    /// The most memory used for new objects before they're either collected
+    /// or moved to the main heap by a minor GC.
+    pub max_nursery_bytes: Option<u32>,
+    /// How long each slice of an incremental GC may take.
+    pub slice_budget: Option<Duration>,
+}
+
+thread_local! {
+    /// Set when the runtime on this thread fails to allocate memory.
+    static OUT_OF_MEMORY: Cell<bool> = const { Cell::new(false) };
+    static OUT_OF_MEMORY_WAKER: RefCell<Option<Waker>> = const { RefCell::new(None) };
+}
+
+unsafe extern "C" fn out_of_memory_callback(_cx: *mut JSContext, _data: *mut c_void) {
+    OUT_OF_MEMORY.set(true);
+    if let Some(waker) = OUT_OF_MEMORY_WAKER.take() {
+        waker.wake();
+    }
+}
+
+/// Resolves once the runtime on this thread runs out of memory, after which
+/// it can't be relied upon anymore.
+pub fn wait_for_out_of_memory() -> impl Future<Output = ()> {
+    std::future::poll_fn(|wcx| {
+        if OUT_OF_MEMORY.get() {
+            Poll::Ready(())
+        } else {
+            OUT_OF_MEMORY_WAKER.set(Some(wcx.waker().clone()));
+            Poll::Pending
+        }
+    })
+}
+
 pub struct ContextWrapper {
     // Important: the context must come first, because it has to be dropped
     // before the runtime, otherwise we get a nasty error at runtime
@@ -50,8 +110,10 @@ impl JsApp {
     pub fn build<Ml: ModuleLoader + 'static, Std: StandardModules + 'static>(
         loader: Option<Ml>,
         modules: Option<Std>,
+        heap: &HeapConfig,
     ) -> Self {
         let rt = mozjs::rust::Runtime::new(ENGINE.clone());
+        Self::configure_heap(rt.cx(), heap);
         let cx = Context::from_runtime(&rt);
         let wrapper = ContextWrapper { _rt: rt, cx };
         Self::new(wrapper, |w| Self::create_runtime(w, loader, modules))
@@ -65,18 +127,35 @@ impl JsApp {
         self.borrow_dependent()
     }
 
+    fn configure_heap(cx: *mut JSContext, heap: &HeapConfig) {
+        let params = [
+            (JSGCParamKey::JSGC_MAX_BYTES, heap.max_bytes),
+            (JSGCParamKey::JSGC_MAX_NURSERY_BYTES, heap.max_nursery_bytes),
+            (
+                JSGCParamKey::JSGC_SLICE_TIME_BUDGET_MS,
+                heap.slice_budget
+                    .map(|b| b.as_millis().try_into().unwrap_or(u32::MAX)),
+            ),
+        ];
+        for (key, value) in params {
+            if let Some(value) = value {
+                unsafe { JS_SetGCParameter(cx, key, value) };
+            }
+        }
+
+        OUT_OF_MEMORY.set(false);
+        unsafe { SetOutOfMemoryCallback(cx, Some(out_of_memory_callback), std::ptr::null_mut()) };
+    }
+
     fn create_runtime<Ml: ModuleLoader + 'static, Std: StandardModules + 'static>(
         wrapper: &ContextWrapper,
         loader: Option<Ml>,
         modules: Option<Std>,
     ) -> Runtime {
-        let mut realm_options = RealmOptions::default();
-        realm_options.creationOptions_.streams_ = true;
-        realm_options.creationOptions_.weakRefs_ = WeakRefSpecifier::EnabledWithCleanupSome;
         let rt_builder = RuntimeBuilder::<Ml, Std>::new()
             .microtask_queue()
             .macrotask_queue()
-            .realm_options(realm_options);
+            .realm_options(realm_options());
 
         let rt_builder = match loader {
             Some(loader) => rt_builder.modules(loader),
@@ -92,38 +171,268 @@ impl JsApp {
     }
 }
 
+/// A realm entered by [`enter_new_realm`]. It's left, and its global is no
+/// longer rooted, once this is dropped.
+pub struct EnteredRealm {
+    _realm: JSAutoRealm,
+    _global: PermanentHeap<*mut JSObject>,
+}
+
+/// Creates a new global object in a realm of its own, set up like the
+/// runtime's own global, and enters that realm. Nothing defined in other
+/// realms is visible from the new one, but they all share the runtime, its
+/// heap and its event loop.
+///
+/// Modules are bound to the realm they're instantiated in, so module mode
+/// needs a new loader as well.
+///
+/// Anything rooted on `cx` is only released along with it, so it should be a
+/// context that doesn't outlive the realm.
+pub fn enter_new_realm<Ml: ModuleLoader + 'static, Std: StandardModules>(
+    cx: &Context,
+    loader: Option<Ml>,
+    modules: Std,
+) -> anyhow::Result<EnteredRealm> {
+    let global = unsafe {
+        JS_NewGlobalObject(
+            cx.as_ptr(),
+            &SIMPLE_GLOBAL_CLASS,
+            std::ptr::null_mut(),
+            OnNewGlobalHookOption::FireOnNewGlobalHook,
+            &*realm_options(),
+        )
+    };
+    if global.is_null() {
+        bail!("Failed to create a new global object");
+    }
+    let global = cx.root(global);
+    let realm = EnteredRealm {
+        _realm: JSAutoRealm::new(cx.as_ptr(), global.get()),
+        _global: PermanentHeap::from_local(&global),
+    };
+    let global = ion::Object::from(global);
+
+    // Classes are only registered once per context, and their prototypes
+    // belong to the realm they were first defined in
+    let inner = unsafe { &mut *cx.get_inner_data().as_ptr() };
+    inner.class_infos.clear();
+    let module_mode = loader.is_some();
+    if let Some(loader) = loader {
+        inner.module_loader = Some(Box::new(loader));
+    }
+
+    let initialized = global.set_as(cx, "global", &(*global).get())
+        && runtime::globals::init_globals(cx, &global)
+        && runtime::globals::init_microtasks(cx, &global)
+        && runtime::globals::init_timers(cx, &global)
+        && if module_mode {
+            modules.init(cx, &global)
+        } else {
+            modules.init_globals(cx, &global)
+        };
+    if !initialized {
+        bail!("Failed to initialize the globals of a new realm");
+    }
+
+    Ok(realm)
+}
+
+fn realm_options() -> RealmOptions {
+    let mut realm_options = RealmOptions::default();
+    realm_options.creationOptions_.streams_ = true;
+    realm_options.creationOptions_.weakRefs_ = WeakRefSpecifier::EnabledWithCleanupSome;
+    realm_options
+}
+
+/// A script or module compiled to a stencil. Stencils don't belong to any
+/// runtime, so every worker thread can instantiate the same one instead of
+/// compiling the code again.
+struct SharedStencil(*mut Stencil);
+
+// Stencils are immutable once compiled, and reference counted atomically
+unsafe impl Send for SharedStencil {}
+unsafe impl Sync for SharedStencil {}
+
+impl Drop for SharedStencil {
+    fn drop(&mut self) {
+        unsafe { StencilRelease(self.0) };
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum StencilKind {
+    Script,
+    Module,
+}
+
+/// The compiled version of some code, which is only compiled by the first
+/// thread that needs it.
+struct CachedStencil {
+    hash: [u8; 32],
+    stencil: once_cell::sync::OnceCell<Arc<SharedStencil>>,
+}
+
+/// The latest compiled version of every script and module, along with the
+/// hash of the code it was compiled from, so reloading only compiles code
+/// that changed.
+static STENCILS: once_cell::sync::Lazy<
+    Mutex<HashMap<(StencilKind, OsString), Arc<CachedStencil>>>,
+> = once_cell::sync::Lazy::new(Default::default);
+
+fn compile_stencil(
+    cx: &Context,
+    kind: StencilKind,
+    code: &str,
+    file_name: &OsStr,
+) -> Result<Arc<SharedStencil>, Option<ErrorReport>> {
+    let hash: [u8; 32] = Sha256::digest(code.as_bytes()).into();
+
+    let cached = {
+        let mut stencils = STENCILS.lock().unwrap();
+        match stencils.get(&(kind, file_name.to_os_string())) {
+            Some(cached) if cached.hash == hash => cached.clone(),
+            _ => {
+                let cached = Arc::new(CachedStencil {
+                    hash,
+                    stencil: Default::default(),
+                });
+                stencils.insert((kind, file_name.to_os_string()), cached.clone());
+                cached
+            }
+        }
+    };
+
+    // Compiled outside the lock, so only threads starting with the same code
+    // wait for each other, rather than all compiling it
+    let stencil = cached.stencil.get_or_try_init(|| {
+        let options = CompileOptionsWrapper::new(cx.as_ptr(), &file_name.to_string_lossy(), 1);
+        let mut source = transform_str_to_source_text(code);
+        let stencil = unsafe {
+            match kind {
+                StencilKind::Script => {
+                    CompileGlobalScriptToStencil(cx.as_ptr(), options.ptr, &mut source)
+                }
+                StencilKind::Module => {
+                    CompileModuleScriptToStencil(cx.as_ptr(), options.ptr, &mut source)
+                }
+            }
+        }
+        .mRawPtr;
+        if stencil.is_null() {
+            return Err(ErrorReport::new_with_exception_stack(cx));
+        }
+
+        Ok(Arc::new(SharedStencil(stencil)))
+    })?;
+
+    Ok(stencil.clone())
+}
+
+fn instantiate_options() -> InstantiateOptions {
+    InstantiateOptions {
+        skipFilenameValidation: false,
+        hideScriptFromDebugger: false,
+        deferDebugMetadata: false,
+    }
+}
+
+/// Evaluates a script in the global of `cx`. The script is only compiled
+/// the first time it's evaluated in the process, and again once its code
+/// changes.
 pub fn evaluate_script(
     cx: &Context,
     code: impl AsRef<str>,
     file_name: impl AsRef<OsStr>,
 ) -> anyhow::Result<ion::Value> {
-    ion::script::Script::compile_and_evaluate(cx, Path::new(&file_name), code.as_ref())
+    let stencil = compile_stencil(cx, StencilKind::Script, code.as_ref(), file_name.as_ref())
+        .map_err(|e| error_report_option_to_anyhow_error(cx, e))?;
+
+    let script = unsafe {
+        InstantiateGlobalStencil(
+            cx.as_ptr(),
+            &instantiate_options(),
+            stencil.0,
+            std::ptr::null_mut(),
+        )
+    };
+    if script.is_null() {
+        return Err(error_report_option_to_anyhow_error(
+            cx,
+            ErrorReport::new_with_exception_stack(cx),
+        ));
+    }
+
+    ion::script::Script::from(cx.root(script))
+        .evaluate(cx)
         .map_err(|e| error_report_to_anyhow_error(cx, e))
 }
 
+/// Instantiates the module at `path` in the current realm, without linking
+/// or evaluating it. Like scripts, modules are only compiled again once
+/// their code changes.
+pub fn compile_module<'cx>(
+    cx: &'cx Context,
+    path: &Path,
+    code: &str,
+) -> Result<ion::Local<'cx, *mut JSObject>, Option<ErrorReport>> {
+    let stencil = compile_stencil(cx, StencilKind::Module, code, path.as_os_str())?;
+
+    let module = unsafe {
+        InstantiateModuleStencil(
+            cx.as_ptr(),
+            &instantiate_options(),
+            stencil.0,
+            std::ptr::null_mut(),
+        )
+    };
+    if module.is_null() {
+        return Err(ErrorReport::new_with_exception_stack(cx));
+    }
+
+    // Imports are resolved relative to the path in the module's data
+    let module = cx.root(module);
+    let data = ModuleData {
+        path: path.to_str().map(String::from),
+    };
+    unsafe { SetModulePrivate(module.get(), &data.to_object(cx).as_value(cx).get()) };
+    Ok(module)
+}
+
+/// Evaluates the module at `path` in the global of `cx`, which needs a
+/// module loader. The module is registered with the loader under its path,
+/// so imports of it resolve to the same module.
 pub fn evaluate_module(
     cx: &Context,
     path: impl AsRef<Path>,
 ) -> anyhow::Result<ion::module::Module> {
     let path = path.as_ref();
+    let code = std::fs::read_to_string(path).context("Failed to read script file")?;
 
-    let file_name = path
-        .file_name()
-        .ok_or(anyhow!("Failed to get file name from script path"))
-        .map(|f| f.to_string_lossy().into_owned())?;
+    let module_error = |e: Option<ErrorReport>, step: &str| {
+        error_report_option_to_anyhow_error(cx, e)
+            .context(format!("Error while loading module during {step} step"))
+    };
 
-    let code = std::fs::read_to_string(path).context("Failed to read script file")?;
+    let module = compile_module(cx, path, &code).map_err(|e| module_error(e, "Compilation"))?;
+    let module = match unsafe { &mut (*cx.get_inner_data().as_ptr()).module_loader } {
+        Some(loader) => loader
+            .register(
+                cx,
+                module.get(),
+                &ModuleRequest::new(cx, path.to_string_lossy()),
+            )
+            .map_err(|e| anyhow!("Failed to register module due to: {e}"))?,
+        None => bail!("No module loader present, cannot load module"),
+    };
+
+    module
+        .instantiate(cx)
+        .map_err(|e| module_error(Some(e.report), "Instantiation"))?;
+    module
+        .evaluate(cx)
+        .map_err(|e| module_error(Some(e.report), "Evaluation"))?;
 
-    Ok(
-        ion::module::Module::compile_and_evaluate(cx, &file_name, Some(path), &code)
-            .map_err(|e| {
-                error_report_option_to_anyhow_error(cx, Some(e.report)).context(format!(
-                    "Error while loading module during {:?} step",
-                    e.kind
-                ))
-            })?
-            .0,
-    )
+    Ok(module)
 }
 
 pub fn error_report_to_anyhow_error(cx: &Context, error_report: ErrorReport) -> anyhow::Error {